@@ -0,0 +1,34 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::env;
+use std::process::Command;
+
+// embed the compiler version and the target, so that plugins built with another compiler or
+// for another target are refused.
+fn main() {
+	let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+	let output = Command::new(&rustc)
+		.arg("--version")
+		.output()
+		.expect("couldn't run rustc --version");
+	let version = String::from_utf8(output.stdout).expect("invalid rustc --version output");
+	println!("cargo:rustc-env=RUSTLET_RUSTC_VERSION={}", version.trim());
+	println!(
+		"cargo:rustc-env=RUSTLET_TARGET={}",
+		env::var("TARGET").expect("TARGET not set")
+	);
+	println!("cargo:rerun-if-env-changed=RUSTC");
+	println!("cargo:rerun-if-changed=build.rs");
+}
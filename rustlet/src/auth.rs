@@ -0,0 +1,445 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Authentication of requests via HTTP Basic and Bearer authentication. Authentication is
+//! configured via [`crate::RustletConfig::auth`]. Each [`AuthConfig`] protects the uris
+//! matching its pattern. Requests that are not authenticated receive a '401 Unauthorized'
+//! response with a WWW-Authenticate header. The principal of authenticated requests is
+//! available to rustlets via the [`crate::principal`] macro.
+
+use crate::rustlet_impls::{FilterAction, FilterPhase, RustletRequest, RustletResponse};
+use hmac::{Hmac, Mac, NewMac};
+use nioruntime_err::{Error, ErrorKind};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The name of the request attribute that holds the principal of authenticated requests.
+pub const PRINCIPAL_ATTR: &str = "rustlet.principal";
+
+/// Verifies the username and password of HTTP Basic authentication.
+pub trait CredentialVerifier: Send + Sync {
+	/// Returns true if the password is valid for the specified user.
+	fn verify(&self, username: &str, password: &str) -> Result<bool, Error>;
+}
+
+/// Validates the tokens of HTTP Bearer authentication.
+pub trait TokenValidator: Send + Sync {
+	/// Returns the principal that the token was issued to, or None if the token is invalid.
+	fn validate(&self, token: &str) -> Result<Option<String>, Error>;
+}
+
+/// The authentication scheme used for a protected pattern.
+#[derive(Clone)]
+pub enum AuthScheme {
+	/// HTTP Basic authentication against the specified [`CredentialVerifier`].
+	Basic(Arc<dyn CredentialVerifier>),
+	/// HTTP Bearer authentication using the specified [`TokenValidator`].
+	Bearer(Arc<dyn TokenValidator>),
+}
+
+/// The authentication configuration of a uri pattern. Patterns are matched in the same way
+/// as the patterns of filters, see [`crate::rustlet_filter`].
+#[derive(Clone)]
+pub struct AuthConfig {
+	/// The pattern of the uris that require authentication.
+	pub pattern: String,
+	/// The realm sent in the WWW-Authenticate header.
+	pub realm: String,
+	/// The authentication scheme.
+	pub scheme: AuthScheme,
+}
+
+/// A [`CredentialVerifier`] backed by an htpasswd style file. Each line of the file contains
+/// a username and a password hash separated by a ':'. Both bcrypt (`$2a$`, `$2b$`, `$2y$`)
+/// and argon2 (`$argon2i$`, `$argon2d$`, `$argon2id$`) hashes are supported. Empty lines
+/// and lines starting with '#' are ignored.
+pub struct HtpasswdVerifier {
+	entries: HashMap<String, String>,
+}
+
+impl HtpasswdVerifier {
+	/// Load the specified htpasswd file.
+	pub fn new(file: &str) -> Result<Self, Error> {
+		let mut contents = String::new();
+		File::open(file)?.read_to_string(&mut contents)?;
+		Self::from_contents(&contents)
+	}
+
+	/// Parse the contents of an htpasswd file.
+	pub fn from_contents(contents: &str) -> Result<Self, Error> {
+		let mut entries = HashMap::new();
+		for (i, line) in contents.lines().enumerate() {
+			let line = line.trim();
+			if line.len() == 0 || line.starts_with('#') {
+				continue;
+			}
+
+			match line.find(':') {
+				Some(pos) => {
+					let hash = &line[(pos + 1)..];
+					if !is_bcrypt(hash) && !hash.starts_with("$argon2") {
+						return Err(ErrorKind::SetupError(format!(
+							"unsupported password hash on line {} of htpasswd file",
+							i + 1
+						))
+						.into());
+					}
+					entries.insert(line[..pos].to_string(), hash.to_string());
+				}
+				None => {
+					return Err(ErrorKind::SetupError(format!(
+						"invalid entry on line {} of htpasswd file",
+						i + 1
+					))
+					.into());
+				}
+			}
+		}
+
+		Ok(HtpasswdVerifier { entries })
+	}
+}
+
+impl CredentialVerifier for HtpasswdVerifier {
+	fn verify(&self, username: &str, password: &str) -> Result<bool, Error> {
+		let hash = match self.entries.get(username) {
+			Some(hash) => hash,
+			None => return Ok(false),
+		};
+
+		if is_bcrypt(hash) {
+			bcrypt::verify(password, hash).map_err(|e| {
+				ErrorKind::InternalError(format!("bcrypt verification failed: {}", e)).into()
+			})
+		} else {
+			argon2::verify_encoded(hash, password.as_bytes()).map_err(|e| {
+				ErrorKind::InternalError(format!("argon2 verification failed: {}", e)).into()
+			})
+		}
+	}
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+	hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")
+}
+
+/// A [`TokenValidator`] for JSON web tokens signed with HMAC-SHA256 (HS256). The signature
+/// and the `exp` and `nbf` claims are validated. If an issuer is specified, the `iss` claim
+/// must match it. The principal is the `sub` claim of the token.
+pub struct JwtHmacValidator {
+	key: Vec<u8>,
+	issuer: Option<String>,
+	leeway: u64,
+}
+
+impl JwtHmacValidator {
+	/// Create a validator using the specified secret key.
+	pub fn new(key: &[u8]) -> Self {
+		JwtHmacValidator {
+			key: key.to_vec(),
+			issuer: None,
+			leeway: 0,
+		}
+	}
+
+	/// Require the `iss` claim of tokens to match the specified issuer.
+	pub fn with_issuer(mut self, issuer: &str) -> Self {
+		self.issuer = Some(issuer.to_string());
+		self
+	}
+
+	/// The number of seconds of clock skew tolerated when validating `exp` and `nbf`.
+	pub fn with_leeway(mut self, leeway: u64) -> Self {
+		self.leeway = leeway;
+		self
+	}
+}
+
+impl TokenValidator for JwtHmacValidator {
+	fn validate(&self, token: &str) -> Result<Option<String>, Error> {
+		let parts: Vec<&str> = token.split('.').collect();
+		if parts.len() != 3 {
+			return Ok(None);
+		}
+
+		let header = match decode_jwt_part(parts[0]) {
+			Some(header) => header,
+			None => return Ok(None),
+		};
+		if header.get("alg").and_then(|alg| alg.as_str()) != Some("HS256") {
+			return Ok(None);
+		}
+
+		let signature = match base64::decode_config(parts[2], base64::URL_SAFE_NO_PAD) {
+			Ok(signature) => signature,
+			Err(_) => return Ok(None),
+		};
+		let mut mac = Hmac::<Sha256>::new_from_slice(&self.key)
+			.map_err(|e| ErrorKind::InternalError(format!("invalid hmac key: {}", e)))?;
+		mac.update(parts[0].as_bytes());
+		mac.update(".".as_bytes());
+		mac.update(parts[1].as_bytes());
+		if mac.verify(&signature).is_err() {
+			return Ok(None);
+		}
+
+		let claims = match decode_jwt_part(parts[1]) {
+			Some(claims) => claims,
+			None => return Ok(None),
+		};
+		let now = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map_err(|e| ErrorKind::InternalError(format!("invalid system time: {}", e)))?
+			.as_secs();
+		match claims.get("exp").and_then(|exp| exp.as_u64()) {
+			Some(exp) if exp.saturating_add(self.leeway) < now => return Ok(None),
+			_ => {}
+		}
+		match claims.get("nbf").and_then(|nbf| nbf.as_u64()) {
+			Some(nbf) if nbf > now.saturating_add(self.leeway) => return Ok(None),
+			_ => {}
+		}
+		match &self.issuer {
+			Some(issuer) => {
+				if claims.get("iss").and_then(|iss| iss.as_str()) != Some(issuer.as_str()) {
+					return Ok(None);
+				}
+			}
+			None => {}
+		}
+
+		Ok(claims
+			.get("sub")
+			.and_then(|sub| sub.as_str())
+			.map(|sub| sub.to_string()))
+	}
+}
+
+// decode a base64url encoded JSON object of a JWT.
+fn decode_jwt_part(part: &str) -> Option<serde_json::Map<String, serde_json::Value>> {
+	let json = base64::decode_config(part, base64::URL_SAFE_NO_PAD).ok()?;
+	match serde_json::from_slice(&json).ok()? {
+		serde_json::Value::Object(map) => Some(map),
+		_ => None,
+	}
+}
+
+// the filter that authenticates requests matching the pattern of the specified configuration.
+pub(crate) fn auth_filter(
+	config: &AuthConfig,
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+	phase: FilterPhase,
+) -> Result<FilterAction, Error> {
+	if phase != FilterPhase::Before {
+		return Ok(FilterAction::Continue);
+	}
+
+	let authorization = request.get_header("Authorization")?;
+	let (scheme_name, principal) = match &config.scheme {
+		AuthScheme::Basic(verifier) => (
+			"Basic",
+			match credentials(&authorization, "Basic") {
+				Some(credentials) => authenticate_basic(verifier.as_ref(), &credentials)?,
+				None => None,
+			},
+		),
+		AuthScheme::Bearer(validator) => (
+			"Bearer",
+			match credentials(&authorization, "Bearer") {
+				Some(token) => validator.validate(&token)?,
+				None => None,
+			},
+		),
+	};
+
+	match principal {
+		Some(principal) => {
+			request.set_attr(PRINCIPAL_ATTR, principal)?;
+			Ok(FilterAction::Continue)
+		}
+		None => {
+			response.set_status(401)?;
+			response.add_header(
+				"WWW-Authenticate",
+				&format!(
+					"{} realm=\"{}\"",
+					scheme_name,
+					config.realm.replace('\\', "\\\\").replace('"', "\\\"")
+				),
+			)?;
+			response.set_content_type("text/plain")?;
+			response.write("401 Unauthorized".as_bytes())?;
+			Ok(FilterAction::Stop)
+		}
+	}
+}
+
+// the credentials of the Authorization header, if it uses the specified scheme.
+fn credentials(authorization: &Option<String>, scheme: &str) -> Option<String> {
+	let authorization = authorization.as_ref()?.trim();
+	let pos = authorization.find(' ')?;
+	match authorization[..pos].eq_ignore_ascii_case(scheme) {
+		true => Some(authorization[pos..].trim().to_string()),
+		false => None,
+	}
+}
+
+// verify base64 encoded 'username:password' credentials. Returns the username if valid.
+fn authenticate_basic(
+	verifier: &dyn CredentialVerifier,
+	credentials: &str,
+) -> Result<Option<String>, Error> {
+	let decoded = match base64::decode(credentials) {
+		Ok(decoded) => decoded,
+		Err(_) => return Ok(None),
+	};
+	let decoded = match std::str::from_utf8(&decoded) {
+		Ok(decoded) => decoded,
+		Err(_) => return Ok(None),
+	};
+
+	match decoded.find(':') {
+		Some(pos) => {
+			let username = &decoded[..pos];
+			match verifier.verify(username, &decoded[(pos + 1)..])? {
+				true => Ok(Some(username.to_string())),
+				false => Ok(None),
+			}
+		}
+		None => Ok(None),
+	}
+}
+
+#[cfg(test)]
+fn make_jwt(key: &[u8], alg: &str, claims: &str) -> String {
+	let header = base64::encode_config(
+		format!("{{\"alg\":\"{}\",\"typ\":\"JWT\"}}", alg),
+		base64::URL_SAFE_NO_PAD,
+	);
+	let claims = base64::encode_config(claims, base64::URL_SAFE_NO_PAD);
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+	mac.update(format!("{}.{}", header, claims).as_bytes());
+	let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+	format!("{}.{}.{}", header, claims, signature)
+}
+
+#[cfg(test)]
+fn now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.as_secs()
+}
+
+#[test]
+fn test_htpasswd() -> Result<(), Error> {
+	let hash = bcrypt::hash("secret", 4).unwrap();
+	let verifier = HtpasswdVerifier::from_contents(&format!(
+		"# users\n\n  alice:{}  \nbob:$argon2id$v=19$m=16,t=2,p=1$c2FsdHNhbHQ$aGFzaA\n",
+		hash
+	))?;
+	assert!(verifier.verify("alice", "secret")?);
+	assert!(!verifier.verify("alice", "wrong")?);
+	assert!(!verifier.verify("carol", "secret")?);
+
+	// the password may contain ':'
+	let hash = bcrypt::hash("a:b", 4).unwrap();
+	let verifier = HtpasswdVerifier::from_contents(&format!("dave:{}", hash))?;
+	assert!(verifier.verify("dave", "a:b")?);
+
+	assert!(HtpasswdVerifier::from_contents("alice").is_err());
+	assert!(HtpasswdVerifier::from_contents("alice:plaintext").is_err());
+	assert!(HtpasswdVerifier::from_contents("alice:{SHA}abc").is_err());
+	assert!(HtpasswdVerifier::from_contents("# only a comment\n").is_ok());
+	Ok(())
+}
+
+#[test]
+fn test_jwt() -> Result<(), Error> {
+	let key = b"the key";
+	let validator = JwtHmacValidator::new(key);
+	let now = now();
+
+	let token = make_jwt(
+		key,
+		"HS256",
+		&format!("{{\"sub\":\"alice\",\"exp\":{}}}", now + 60),
+	);
+	assert_eq!(validator.validate(&token)?, Some("alice".to_string()));
+
+	// no sub
+	let token = make_jwt(key, "HS256", "{\"name\":\"alice\"}");
+	assert_eq!(validator.validate(&token)?, None);
+
+	// expired
+	let token = make_jwt(
+		key,
+		"HS256",
+		&format!("{{\"sub\":\"alice\",\"exp\":{}}}", now - 60),
+	);
+	assert_eq!(validator.validate(&token)?, None);
+	let lenient = JwtHmacValidator::new(key).with_leeway(120);
+	assert_eq!(lenient.validate(&token)?, Some("alice".to_string()));
+
+	// not valid yet
+	let token = make_jwt(
+		key,
+		"HS256",
+		&format!("{{\"sub\":\"alice\",\"nbf\":{}}}", now + 60),
+	);
+	assert_eq!(validator.validate(&token)?, None);
+	assert_eq!(lenient.validate(&token)?, Some("alice".to_string()));
+
+	// large claims and leeway don't overflow
+	let token = make_jwt(
+		key,
+		"HS256",
+		&format!(
+			"{{\"sub\":\"alice\",\"exp\":{},\"nbf\":{}}}",
+			u64::MAX,
+			u64::MAX
+		),
+	);
+	assert_eq!(validator.validate(&token)?, None);
+	let max_leeway = JwtHmacValidator::new(key).with_leeway(u64::MAX);
+	assert_eq!(max_leeway.validate(&token)?, Some("alice".to_string()));
+
+	// wrong key, tampered claims and other algorithms
+	let token = make_jwt(b"other key", "HS256", "{\"sub\":\"alice\"}");
+	assert_eq!(validator.validate(&token)?, None);
+	let token = make_jwt(key, "HS256", "{\"sub\":\"alice\"}");
+	let parts: Vec<&str> = token.split('.').collect();
+	let claims = base64::encode_config("{\"sub\":\"admin\"}", base64::URL_SAFE_NO_PAD);
+	let tampered = format!("{}.{}.{}", parts[0], claims, parts[2]);
+	assert_eq!(validator.validate(&tampered)?, None);
+	let token = make_jwt(key, "none", "{\"sub\":\"alice\"}");
+	assert_eq!(validator.validate(&token)?, None);
+	assert_eq!(validator.validate("abc")?, None);
+	assert_eq!(validator.validate("a.b.c")?, None);
+
+	// issuer
+	let validator = JwtHmacValidator::new(key).with_issuer("me");
+	let token = make_jwt(key, "HS256", "{\"sub\":\"alice\",\"iss\":\"me\"}");
+	assert_eq!(validator.validate(&token)?, Some("alice".to_string()));
+	let token = make_jwt(key, "HS256", "{\"sub\":\"alice\",\"iss\":\"you\"}");
+	assert_eq!(validator.validate(&token)?, None);
+	let token = make_jwt(key, "HS256", "{\"sub\":\"alice\"}");
+	assert_eq!(validator.validate(&token)?, None);
+	Ok(())
+}
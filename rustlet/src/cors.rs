@@ -0,0 +1,395 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cross-origin resource sharing (CORS). CORS is configured via [`crate::RustletConfig::cors`].
+//! Each [`CorsConfig`] applies to the uris that match its pattern. Responses to requests from
+//! allowed origins receive the Access-Control-Allow-Origin header (and related headers). If
+//! more than one configuration matches a uri, the first one in the list is used.
+//!
+//! Preflight requests are recognized by their Access-Control-Request-Method header and are
+//! answered by the filter with '204 No Content' and the allowed methods and headers, without
+//! executing the rustlet. Preflight requests from origins that are not allowed, or for
+//! methods that are not allowed, are answered with '403 Forbidden'.
+//!
+//! Please note that the HTTP server currently only dispatches GET and POST requests and
+//! responds to OPTIONS requests with '400 Bad Request' before the filters are run, so
+//! browsers only receive the preflight response once the server dispatches OPTIONS requests.
+//! Until then, only simple cross-origin requests (GET and POST requests without custom
+//! headers) succeed.
+
+use crate::rustlet_impls::{
+	filter_matches, glob_matches, FilterAction, FilterPhase, RustletRequest, RustletResponse,
+};
+use nioruntime_err::Error;
+
+/// The CORS configuration of a uri pattern.
+#[derive(Clone)]
+pub struct CorsConfig {
+	/// The pattern of the uris that this configuration applies to. Patterns are matched in
+	/// the same way as the patterns of filters, see [`crate::rustlet_filter`].
+	pub pattern: String,
+	/// The allowed origins, for example `https://app.example.com`. Origins that contain '*'
+	/// are globs, for example `https://*.example.com`. The origin `*` allows all origins.
+	pub allowed_origins: Vec<String>,
+	/// The allowed methods. The default value is GET and POST.
+	pub allowed_methods: Vec<String>,
+	/// The allowed request headers. The header `*` allows all headers.
+	pub allowed_headers: Vec<String>,
+	/// The response headers that are exposed to the client.
+	pub exposed_headers: Vec<String>,
+	/// Whether credentials (cookies and authorization headers) are allowed.
+	pub allow_credentials: bool,
+	/// The number of seconds that the result of a preflight request may be cached.
+	pub max_age: Option<u64>,
+}
+
+impl Default for CorsConfig {
+	fn default() -> Self {
+		CorsConfig {
+			pattern: "/".to_string(),
+			allowed_origins: vec![],
+			allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+			allowed_headers: vec![],
+			exposed_headers: vec![],
+			allow_credentials: false,
+			max_age: None,
+		}
+	}
+}
+
+impl CorsConfig {
+	fn is_origin_allowed(&self, origin: &str) -> bool {
+		self.allowed_origins.iter().any(|allowed| {
+			allowed == "*"
+				|| match allowed.contains('*') {
+					true => glob_matches(allowed.as_bytes(), origin.as_bytes()),
+					false => allowed.eq_ignore_ascii_case(origin),
+				}
+		})
+	}
+
+	fn allow_origin_value(&self, origin: &str) -> String {
+		// a wildcard may not be used with credentials, so the origin is echoed instead
+		match self.allowed_origins.iter().any(|allowed| allowed == "*") && !self.allow_credentials {
+			true => "*".to_string(),
+			false => origin.to_string(),
+		}
+	}
+
+	// the headers of the response to a preflight request, or None if the method is not
+	// allowed. Only the requested headers that are allowed are listed.
+	fn preflight_headers(
+		&self,
+		request_method: &str,
+		request_headers: Option<&str>,
+	) -> Option<Vec<(String, String)>> {
+		if !self
+			.allowed_methods
+			.iter()
+			.any(|method| method.eq_ignore_ascii_case(request_method.trim()))
+		{
+			return None;
+		}
+
+		let mut headers = vec![(
+			"Access-Control-Allow-Methods".to_string(),
+			self.allowed_methods.join(", "),
+		)];
+		match request_headers {
+			Some(request_headers) => {
+				let allow_all = self.allowed_headers.iter().any(|h| h == "*");
+				let allowed: Vec<&str> = request_headers
+					.split(',')
+					.map(|h| h.trim())
+					.filter(|h| {
+						h.len() > 0
+							&& (allow_all
+								|| self
+									.allowed_headers
+									.iter()
+									.any(|allowed| allowed.eq_ignore_ascii_case(h)))
+					})
+					.collect();
+				if allowed.len() > 0 {
+					headers.push((
+						"Access-Control-Allow-Headers".to_string(),
+						allowed.join(", "),
+					));
+				}
+			}
+			None => {}
+		}
+		match self.max_age {
+			Some(max_age) => {
+				headers.push(("Access-Control-Max-Age".to_string(), format!("{}", max_age)))
+			}
+			None => {}
+		}
+		Some(headers)
+	}
+}
+
+// the filter that adds CORS headers to the responses of the uris that match the pattern of
+// one of the configurations and answers preflight requests. Only the first matching
+// configuration is applied so that a response never has more than one
+// Access-Control-Allow-Origin header.
+pub(crate) fn cors_filter(
+	configs: &[CorsConfig],
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+	phase: FilterPhase,
+) -> Result<FilterAction, Error> {
+	if phase != FilterPhase::Before {
+		return Ok(FilterAction::Continue);
+	}
+
+	let uri = request.get_uri()?;
+	let config = match configs
+		.iter()
+		.find(|config| filter_matches(&config.pattern, &uri))
+	{
+		Some(config) => config,
+		None => return Ok(FilterAction::Continue),
+	};
+
+	let origin = match request.get_header("Origin")? {
+		Some(origin) => origin,
+		None => return Ok(FilterAction::Continue),
+	};
+
+	let request_method = request.get_header("Access-Control-Request-Method")?;
+
+	if !config.is_origin_allowed(&origin) {
+		return match request_method {
+			Some(_) => forbidden(response),
+			// the browser blocks the response as the CORS headers are missing
+			None => Ok(FilterAction::Continue),
+		};
+	}
+
+	// a preflight request is answered without executing the rustlet
+	let preflight_headers = match request_method {
+		Some(request_method) => {
+			let request_headers = request.get_header("Access-Control-Request-Headers")?;
+			match config.preflight_headers(&request_method, request_headers.as_deref()) {
+				Some(preflight_headers) => Some(preflight_headers),
+				None => return forbidden(response),
+			}
+		}
+		None => None,
+	};
+
+	response.add_header(
+		"Access-Control-Allow-Origin",
+		&config.allow_origin_value(&origin),
+	)?;
+	response.add_header("Vary", "Origin")?;
+	if config.allow_credentials {
+		response.add_header("Access-Control-Allow-Credentials", "true")?;
+	}
+
+	match preflight_headers {
+		Some(preflight_headers) => {
+			response.set_status(204)?;
+			for (name, value) in &preflight_headers {
+				response.add_header(name, value)?;
+			}
+			Ok(FilterAction::Stop)
+		}
+		None => {
+			if config.exposed_headers.len() > 0 {
+				response.add_header(
+					"Access-Control-Expose-Headers",
+					&config.exposed_headers.join(", "),
+				)?;
+			}
+			Ok(FilterAction::Continue)
+		}
+	}
+}
+
+// reject a preflight request.
+fn forbidden(response: &mut RustletResponse) -> Result<FilterAction, Error> {
+	response.set_status(403)?;
+	response.set_content_type("text/plain")?;
+	response.write("403 Forbidden".as_bytes())?;
+	Ok(FilterAction::Stop)
+}
+
+#[test]
+fn test_cors_config() {
+	let config = CorsConfig {
+		allowed_origins: vec![
+			"https://app.example.com".to_string(),
+			"https://*.example.org".to_string(),
+		],
+		allowed_methods: vec!["GET".to_string(), "PUT".to_string()],
+		allowed_headers: vec!["Content-Type".to_string(), "X-Token".to_string()],
+		max_age: Some(600),
+		..CorsConfig::default()
+	};
+	assert!(config.is_origin_allowed("https://APP.example.com"));
+	assert!(config.is_origin_allowed("https://api.example.org"));
+	assert!(!config.is_origin_allowed("https://example.com"));
+	assert!(!config.is_origin_allowed("http://app.example.com"));
+	assert_eq!(
+		config.allow_origin_value("https://app.example.com"),
+		"https://app.example.com"
+	);
+
+	let headers = config.preflight_headers("put", Some("x-token, X-Other,content-type"));
+	assert_eq!(
+		headers,
+		Some(vec![
+			(
+				"Access-Control-Allow-Methods".to_string(),
+				"GET, PUT".to_string()
+			),
+			(
+				"Access-Control-Allow-Headers".to_string(),
+				"x-token, content-type".to_string()
+			),
+			("Access-Control-Max-Age".to_string(), "600".to_string()),
+		])
+	);
+	assert_eq!(config.preflight_headers("DELETE", None), None);
+
+	let config = CorsConfig {
+		allowed_origins: vec!["*".to_string()],
+		allowed_headers: vec!["*".to_string()],
+		..CorsConfig::default()
+	};
+	assert!(config.is_origin_allowed("https://any.example.com"));
+	assert_eq!(config.allow_origin_value("https://any.example.com"), "*");
+	assert_eq!(
+		config.preflight_headers("POST", Some("X-Anything")),
+		Some(vec![
+			(
+				"Access-Control-Allow-Methods".to_string(),
+				"GET, POST".to_string()
+			),
+			(
+				"Access-Control-Allow-Headers".to_string(),
+				"X-Anything".to_string()
+			),
+		])
+	);
+
+	// a wildcard may not be used with credentials
+	let config = CorsConfig {
+		allow_credentials: true,
+		..config
+	};
+	assert_eq!(
+		config.allow_origin_value("https://any.example.com"),
+		"https://any.example.com"
+	);
+}
+
+#[test]
+fn test_cors_filter() -> Result<(), Error> {
+	use crate::rustlet_impls::http_request;
+	use crate::{HttpConfig, RustletConfig, RustletContainer};
+	use std::time::Duration;
+
+	let port = 18_730;
+	let root_dir = format!(
+		"{}/rustlet_cors_{}",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	let container = RustletContainer::builder()
+		.config(RustletConfig {
+			http_config: HttpConfig {
+				host: "127.0.0.1".to_string(),
+				port,
+				root_dir: root_dir.clone(),
+				..HttpConfig::default()
+			},
+			cors: vec![
+				CorsConfig {
+					pattern: "/api/".to_string(),
+					allowed_origins: vec!["https://app.example.com".to_string()],
+					allowed_headers: vec!["Content-Type".to_string()],
+					exposed_headers: vec!["X-Total".to_string()],
+					max_age: Some(600),
+					..CorsConfig::default()
+				},
+				CorsConfig {
+					allowed_origins: vec!["*".to_string()],
+					..CorsConfig::default()
+				},
+			],
+			..RustletConfig::default()
+		})
+		.rustlet("items", |_request, response| response.write(b"items"))
+		.mapping("/api/items", "items")
+		.mapping("/items", "items")
+		.build()?;
+
+	let get = |uri: &str, headers: &str| {
+		http_request(
+			port,
+			&format!(
+				"GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+				uri, headers
+			),
+		)
+	};
+	let origin = "Origin: https://app.example.com\r\n";
+	let preflight = "Access-Control-Request-Method: POST\r\n";
+	let responses = (|| -> Result<Vec<String>, Error> {
+		Ok(vec![
+			get("/api/items", origin)?,
+			get("/api/items", "Origin: https://example.com\r\n")?,
+			get("/items", "Origin: https://example.com\r\n")?,
+			get(
+				"/api/items",
+				&format!(
+					"{}{}Access-Control-Request-Headers: content-type, x-other\r\n",
+					origin, preflight
+				),
+			)?,
+			get(
+				"/api/items",
+				&format!("{}Access-Control-Request-Method: DELETE\r\n", origin),
+			)?,
+			get(
+				"/api/items",
+				&format!("Origin: https://example.com\r\n{}", preflight),
+			)?,
+		])
+	})();
+	container.stop(Duration::from_secs(5))?;
+	let _ = std::fs::remove_dir_all(&root_dir);
+
+	let responses = responses?;
+	// a simple request from an allowed origin
+	assert!(responses[0].contains("Access-Control-Allow-Origin: https://app.example.com\r\n"));
+	assert!(responses[0].contains("Access-Control-Expose-Headers: X-Total\r\n"));
+	assert!(responses[0].contains("items"));
+	// the first matching configuration applies
+	assert!(!responses[1].contains("Access-Control-Allow-Origin"));
+	assert!(responses[1].contains("items"));
+	assert!(responses[2].contains("Access-Control-Allow-Origin: *\r\n"));
+	// preflight requests are answered without executing the rustlet
+	assert!(responses[3].starts_with("HTTP/1.1 204"), "{}", responses[3]);
+	assert!(responses[3].contains("Access-Control-Allow-Methods: GET, POST\r\n"));
+	assert!(responses[3].contains("Access-Control-Allow-Headers: content-type\r\n"));
+	assert!(responses[3].contains("Access-Control-Max-Age: 600\r\n"));
+	assert!(!responses[3].contains("items"));
+	assert!(responses[4].starts_with("HTTP/1.1 403"), "{}", responses[4]);
+	assert!(responses[5].starts_with("HTTP/1.1 403"), "{}", responses[5]);
+	Ok(())
+}
@@ -0,0 +1,262 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cross-site request forgery (CSRF) protection. Each session has a CSRF token which can be
+//! retrieved via the [`crate::csrf_token`] macro or included in forms via the `<@csrf>` RSP
+//! tag. If [`crate::RustletConfig::csrf`] is configured, requests that modify state and match
+//! its pattern must either include the token or have a same-origin Origin header. Otherwise a
+//! '403 Forbidden' response is returned.
+//!
+//! Every method other than GET is considered to modify state and is checked, so PUT, DELETE
+//! and PATCH requests are covered as soon as the HTTP server dispatches them (it currently
+//! only dispatches GET and POST requests). The token is only accepted from the form body or
+//! the [`CSRF_HEADER`] header, never from the query string, because urls end up in logs and
+//! Referer headers.
+
+use crate::rustlet_impls::{
+	FilterAction, FilterPhase, RustletRequest, RustletResponse, SessionString,
+};
+use nioruntime_err::Error;
+use nioruntime_http::HttpMethod;
+
+/// The name of the form parameter that holds the CSRF token.
+pub const CSRF_PARAMETER: &str = "csrf_token";
+/// The name of the header that may hold the CSRF token as an alternative to the form
+/// parameter.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+// the name of the session entry that stores the token.
+const CSRF_SESSION_KEY: &str = "rustlet.csrf_token";
+
+/// The configuration of the CSRF filter.
+#[derive(Clone)]
+pub struct CsrfConfig {
+	/// The pattern of the uris that are protected. Patterns are matched in the same way as
+	/// the patterns of filters, see [`crate::rustlet_filter`].
+	pub pattern: String,
+}
+
+impl Default for CsrfConfig {
+	fn default() -> Self {
+		CsrfConfig {
+			pattern: "/".to_string(),
+		}
+	}
+}
+
+// get the CSRF token of the request's session, creating it if it doesn't exist yet.
+pub(crate) fn get_or_create_token(request: &mut RustletRequest) -> Result<String, Error> {
+	match request.get_session::<SessionString>(CSRF_SESSION_KEY)? {
+		Some(token) => Ok(token.0),
+		None => {
+			let bytes: [u8; 32] = rand::random();
+			let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+			request.set_session(CSRF_SESSION_KEY, SessionString(token.clone()))?;
+			Ok(token)
+		}
+	}
+}
+
+// the hidden form input emitted by the <@csrf> RSP tag.
+pub(crate) fn hidden_input(token: &str) -> String {
+	format!(
+		"<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+		CSRF_PARAMETER,
+		crate::html_escape(token)
+	)
+}
+
+// the filter that rejects state changing (non-GET) requests without a valid token or a same-origin
+// Origin header.
+pub(crate) fn csrf_filter(
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+	phase: FilterPhase,
+) -> Result<FilterAction, Error> {
+	if phase != FilterPhase::Before || request.get_http_method()? == HttpMethod::Get {
+		return Ok(FilterAction::Continue);
+	}
+
+	if is_same_origin(request)? || has_valid_token(request)? {
+		return Ok(FilterAction::Continue);
+	}
+
+	response.set_status(403)?;
+	response.set_content_type("text/plain")?;
+	response.write("403 Forbidden".as_bytes())?;
+	Ok(FilterAction::Stop)
+}
+
+// returns true if the Origin header matches the Host header of the request.
+fn is_same_origin(request: &mut RustletRequest) -> Result<bool, Error> {
+	let origin = match request.get_header("Origin")? {
+		Some(origin) => origin,
+		None => return Ok(false),
+	};
+	let host = match request.get_header("Host")? {
+		Some(host) => host,
+		None => return Ok(false),
+	};
+
+	let origin_host = match origin.find("://") {
+		Some(pos) => &origin[(pos + 3)..],
+		None => return Ok(false),
+	};
+
+	Ok(origin_host
+		.trim_end_matches('/')
+		.eq_ignore_ascii_case(host.trim()))
+}
+
+// returns true if the request includes the token of its session.
+fn has_valid_token(request: &mut RustletRequest) -> Result<bool, Error> {
+	// without a session cookie there is no token to compare to
+	if request.get_cookie("rustletsessionid")?.is_none() {
+		return Ok(false);
+	}
+
+	let expected = match request.get_session::<SessionString>(CSRF_SESSION_KEY)? {
+		Some(expected) => expected.0,
+		None => return Ok(false),
+	};
+
+	let token = match request.get_header(CSRF_HEADER)? {
+		Some(token) => Some(token),
+		None => {
+			let content = request.get_content()?;
+			let content = std::str::from_utf8(&content).unwrap_or("");
+			querystring::querify(content)
+				.iter()
+				.find(|(name, _)| *name == CSRF_PARAMETER)
+				.map(|(_, value)| value.to_string())
+		}
+	};
+
+	Ok(match token {
+		Some(token) => constant_time_eq(token.trim().as_bytes(), expected.as_bytes()),
+		None => false,
+	})
+}
+
+// compare two byte slices in constant time.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+	if a.len() != b.len() {
+		return false;
+	}
+
+	a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// sends a POST request with the specified additional headers and form content.
+#[cfg(test)]
+fn post(port: u16, headers: &str, content: &str) -> Result<String, Error> {
+	crate::rustlet_impls::http_request(
+		port,
+		&format!(
+			"POST /submit HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\
+			Content-Type: application/x-www-form-urlencoded\r\nContent-Length: {}\r\n\r\n{}",
+			headers,
+			content.len(),
+			content
+		),
+	)
+}
+
+// returns the text of the response between the specified prefix and the next ';'.
+#[cfg(test)]
+fn find_value(response: &str, prefix: &str) -> String {
+	match response.find(prefix) {
+		Some(pos) => {
+			let value = &response[(pos + prefix.len())..];
+			value[..value.find(';').unwrap_or(value.len())].to_string()
+		}
+		None => "".to_string(),
+	}
+}
+
+#[test]
+fn test_csrf_filter() -> Result<(), Error> {
+	use crate::rustlet_impls::http_get;
+	use crate::{HttpConfig, RustletConfig, RustletContainer};
+	use std::time::Duration;
+
+	let port = 18_720;
+	let root_dir = format!(
+		"{}/rustlet_csrf_{}",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	let container = RustletContainer::builder()
+		.config(RustletConfig {
+			http_config: HttpConfig {
+				host: "127.0.0.1".to_string(),
+				port,
+				root_dir: root_dir.clone(),
+				..HttpConfig::default()
+			},
+			csrf: Some(CsrfConfig::default()),
+			..RustletConfig::default()
+		})
+		.rustlet("token", |request, response| {
+			let token = request.get_csrf_token()?;
+			response.write(format!("token={};", token).as_bytes())
+		})
+		.rustlet("submit", |_request, response| response.write(b"submitted"))
+		.mapping("/token", "token")
+		.mapping("/submit", "submit")
+		.build()?;
+
+	let responses = (|| -> Result<Vec<String>, Error> {
+		let response = http_get(port, "/token")?;
+		let token = find_value(&response, "token=");
+		let cookie = format!(
+			"Cookie: rustletsessionid={}\r\n",
+			find_value(&response, "rustletsessionid=")
+		);
+		Ok(vec![
+			// the token of the session in the header or the form
+			post(port, &format!("{}X-CSRF-Token: {}\r\n", cookie, token), "")?,
+			post(port, &cookie, &format!("{}={}", CSRF_PARAMETER, token))?,
+			// a same-origin request
+			post(port, "Origin: http://localhost\r\n", "")?,
+			// no token, a token without its session, a wrong or empty token and a cross-origin
+			// request
+			post(port, "", "")?,
+			post(port, &format!("X-CSRF-Token: {}\r\n", token), "")?,
+			post(port, &format!("{}X-CSRF-Token: {}0\r\n", cookie, token), "")?,
+			post(port, &cookie, &format!("{}=", CSRF_PARAMETER))?,
+			post(port, "Origin: http://example.com\r\n", "")?,
+			// the token is never accepted from the query string
+			crate::rustlet_impls::http_request(
+				port,
+				&format!(
+					"POST /submit?{}={} HTTP/1.1\r\nHost: localhost\r\n{}\
+					Connection: close\r\nContent-Length: 0\r\n\r\n",
+					CSRF_PARAMETER, token, cookie
+				),
+			)?,
+		])
+	})();
+	container.stop(Duration::from_secs(5))?;
+	let _ = std::fs::remove_dir_all(&root_dir);
+
+	let responses = responses?;
+	for response in &responses[..3] {
+		assert!(response.contains("submitted"), "{}", response);
+	}
+	for response in &responses[3..] {
+		assert!(response.starts_with("HTTP/1.1 403"), "{}", response);
+	}
+	Ok(())
+}
@@ -0,0 +1,91 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Declaration of rustlets with the [`rustlet`] attribute. A function with the signature of a
+//! rustlet is annotated with the path that it is mapped to and, optionally, the http method
+//! that it accepts. The declared rustlets are added to the container and mapped by
+//! [`crate::rustlet_init`], so no call to [`crate::rustlet`] or [`crate::rustlet_mapping`] is
+//! needed. The rustlet is named after the function, including its module path.
+//!
+//! The function may take a `&mut RustletRequest`, a `&mut RustletResponse` and any number of
+//! parameters that are extracted from the request, such as `Query<T>` or `Json<T>`, in any
+//! order. See [`crate::extract`].
+//!
+//! Declaring the same path twice is a compile error, or a link error if the declarations are
+//! in different crates. Declared paths that are already mapped when the declared rustlets are
+//! added are rejected with an error. Requests with another method than the declared one
+//! receive a 405 Method Not Allowed response. The macros that read the request
+//! and write the response, such as [`crate::request`] and [`crate::response`], may be used
+//! within declared rustlets.
+//!
+//! The attribute has the same name as the [`crate::rustlet`] macro, so it is imported from
+//! this module rather than from the root of the crate.
+//!
+//! # Examples
+//! ```no_run
+//! use nioruntime_err::Error;
+//! use librustlet::*;
+//! use librustlet::declaration::rustlet as declare;
+//! use nioruntime_log::*;
+//!
+//! debug!();
+//!
+//! #[declare(path = "/hello", method = "GET")]
+//! fn hello(_request: &mut RustletRequest, _response: &mut RustletResponse) -> Result<(), Error> {
+//!     response!("hello world!");
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     // hello is added and mapped to /hello here
+//!     rustlet_init!(RustletConfig::default());
+//! }
+//! ```
+
+use crate::rustlet_impls::{RustletRequest, RustletResponse};
+use nioruntime_err::Error;
+use nioruntime_http::HttpMethod;
+
+pub use librustlet_macros::rustlet;
+
+/// A rustlet declared with the [`rustlet`] attribute. Declarations are created by the
+/// attribute and collected when the container is initialized.
+pub struct RustletDeclaration {
+	/// The name of the rustlet, which is the module path and name of the function.
+	pub name: &'static str,
+	/// The path that the rustlet is mapped to.
+	pub path: &'static str,
+	/// The http method that the rustlet accepts or None if it accepts all methods.
+	pub method: Option<HttpMethod>,
+	/// The function that handles the request.
+	pub handler: fn(&mut RustletRequest, &mut RustletResponse) -> Result<(), Error>,
+}
+
+inventory::collect!(RustletDeclaration);
+
+// returns the declared rustlets, sorted by path.
+pub(crate) fn declared_rustlets() -> Vec<&'static RustletDeclaration> {
+	let mut declarations: Vec<&'static RustletDeclaration> =
+		inventory::iter::<RustletDeclaration>.into_iter().collect();
+	declarations.sort_by_key(|declaration| declaration.path);
+	declarations
+}
+
+// the name of the http method, as used in the Allow header.
+pub(crate) fn method_name(method: &HttpMethod) -> &'static str {
+	match method {
+		HttpMethod::Get => "GET",
+		HttpMethod::Post => "POST",
+	}
+}
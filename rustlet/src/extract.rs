@@ -0,0 +1,438 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed extraction of values from the request. Rustlets declared with the
+//! [`crate::declaration::rustlet`] attribute may take any type that implements [`FromRequest`]
+//! as a parameter, in addition to the [`RustletRequest`] and [`RustletResponse`]. The
+//! parameters are extracted before the function is called, and if a value can't be
+//! extracted, a 400 Bad Request response describing the problem is sent instead.
+//!
+//! The following extractors are provided:
+//!
+//! * [`Query<T>`] deserializes the query string into T.
+//! * [`Form<T>`] deserializes a url encoded form in the content of the request into T.
+//! * [`Json<T>`] deserializes the JSON content of the request into T.
+//! * [`Header<H>`] parses a header into H, see [`TypedHeader`].
+//!
+//! An `Option` of an extractor is None if the value can't be extracted.
+//!
+//! There's no extractor for path parameters, as mappings are exact paths and the HTTP server
+//! doesn't pass requests for other paths to the container. Values such as ids are passed
+//! in the query string and extracted with [`Query<T>`] instead.
+//!
+//! # Examples
+//! ```no_run
+//! use nioruntime_err::Error;
+//! use librustlet::*;
+//! use librustlet::declaration::rustlet as declare;
+//! use librustlet::extract::{Header, Query, UserAgent};
+//! use nioruntime_log::*;
+//! use serde::Deserialize;
+//!
+//! debug!();
+//!
+//! #[derive(Deserialize)]
+//! struct Search {
+//!     term: String,
+//!     page: Option<u32>,
+//! }
+//!
+//! #[declare(path = "/search", method = "GET")]
+//! fn search(
+//!     Query(search): Query<Search>,
+//!     Header(agent): Header<UserAgent>,
+//! ) -> Result<(), Error> {
+//!     response!(
+//!         "term={},page={},agent={}",
+//!         search.term,
+//!         search.page.unwrap_or(1),
+//!         agent.0
+//!     );
+//!     Ok(())
+//! }
+//!
+//! fn main() {
+//!     rustlet_init!(RustletConfig::default());
+//! }
+//! ```
+
+use crate::rustlet_impls::{RustletRequest, RustletResponse};
+use nioruntime_err::Error;
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+
+/// The reason why a value couldn't be extracted.
+#[derive(Debug)]
+pub enum ExtractError {
+	/// The request doesn't contain a valid value. A 400 Bad Request response with the message
+	/// is sent.
+	Invalid(String),
+	/// The request couldn't be read. The error is handled like errors returned by rustlets.
+	Error(Error),
+}
+
+impl From<Error> for ExtractError {
+	fn from(error: Error) -> Self {
+		ExtractError::Error(error)
+	}
+}
+
+/// A type that can be extracted from a request.
+pub trait FromRequest: Sized {
+	/// Extract the value from the request.
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError>;
+}
+
+impl<T: FromRequest> FromRequest for Option<T> {
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError> {
+		match T::from_request(request) {
+			Ok(value) => Ok(Some(value)),
+			Err(ExtractError::Invalid(_)) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+}
+
+/// Extract a value from the request. If the request doesn't contain a valid value, a 400 Bad
+/// Request response is sent and None is returned. This is called by rustlets declared with
+/// the [`crate::declaration::rustlet`] attribute for each of their parameters.
+pub fn extract_or_reject<T: FromRequest>(
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+) -> Result<Option<T>, Error> {
+	match T::from_request(request) {
+		Ok(value) => Ok(Some(value)),
+		Err(ExtractError::Invalid(message)) => {
+			response.set_status(400)?;
+			response.set_content_type("text/plain")?;
+			response.write(format!("400 Bad Request: {}", message).as_bytes())?;
+			Ok(None)
+		}
+		Err(ExtractError::Error(e)) => Err(e),
+	}
+}
+
+// checks the content type of the request, if it has one.
+fn check_content_type(request: &mut RustletRequest, expected: &str) -> Result<(), ExtractError> {
+	match request.get_header("Content-Type")? {
+		Some(content_type) => {
+			let mime = content_type.split(';').next().unwrap_or("").trim();
+			match mime.eq_ignore_ascii_case(expected) {
+				true => Ok(()),
+				false => Err(ExtractError::Invalid(format!(
+					"expected content type {}, found {}",
+					expected, mime
+				))),
+			}
+		}
+		None => Ok(()),
+	}
+}
+
+/// The query string of the request, deserialized into T.
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError> {
+		let query = request.get_query()?;
+		match serde_urlencoded::from_str(&query) {
+			Ok(value) => Ok(Query(value)),
+			Err(e) => Err(ExtractError::Invalid(format!("invalid query: {}", e))),
+		}
+	}
+}
+
+/// A url encoded form in the content of the request, deserialized into T.
+#[derive(Debug)]
+pub struct Form<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Form<T> {
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError> {
+		check_content_type(request, "application/x-www-form-urlencoded")?;
+		let content = request.get_content()?;
+		match serde_urlencoded::from_bytes(&content) {
+			Ok(value) => Ok(Form(value)),
+			Err(e) => Err(ExtractError::Invalid(format!("invalid form: {}", e))),
+		}
+	}
+}
+
+/// The JSON content of the request, deserialized into T.
+#[derive(Debug)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError> {
+		check_content_type(request, "application/json")?;
+		let content = request.get_content()?;
+		match serde_json::from_slice(&content) {
+			Ok(value) => Ok(Json(value)),
+			Err(e) => Err(ExtractError::Invalid(format!("invalid JSON: {}", e))),
+		}
+	}
+}
+
+/// A header with a typed value. See [`Header`].
+pub trait TypedHeader: Sized {
+	/// The name of the header.
+	const NAME: &'static str;
+	/// Parse the value of the header.
+	fn parse(value: &str) -> Result<Self, String>;
+}
+
+/// A header of the request, parsed into H. The request must contain the header, use
+/// `Option<Header<H>>` for optional headers.
+#[derive(Debug)]
+pub struct Header<H>(pub H);
+
+impl<H: TypedHeader> FromRequest for Header<H> {
+	fn from_request(request: &mut RustletRequest) -> Result<Self, ExtractError> {
+		match request.get_header(H::NAME)? {
+			Some(value) => match H::parse(&value) {
+				Ok(value) => Ok(Header(value)),
+				Err(e) => Err(ExtractError::Invalid(format!(
+					"invalid header {}: {}",
+					H::NAME,
+					e
+				))),
+			},
+			None => Err(ExtractError::Invalid(format!("missing header {}", H::NAME))),
+		}
+	}
+}
+
+/// The User-Agent header.
+#[derive(Debug, Clone)]
+pub struct UserAgent(pub String);
+
+impl TypedHeader for UserAgent {
+	const NAME: &'static str = "User-Agent";
+	fn parse(value: &str) -> Result<Self, String> {
+		Ok(UserAgent(value.to_string()))
+	}
+}
+
+/// The Host header, without the port.
+#[derive(Debug, Clone)]
+pub struct Host(pub String);
+
+impl TypedHeader for Host {
+	const NAME: &'static str = "Host";
+	fn parse(value: &str) -> Result<Self, String> {
+		let host = match value.rfind(':') {
+			Some(i) if !value[i..].contains(']') => &value[..i],
+			_ => value,
+		};
+		match host.is_empty() {
+			true => Err("empty host".to_string()),
+			false => Ok(Host(host.to_lowercase())),
+		}
+	}
+}
+
+/// The Content-Length header.
+#[derive(Debug, Clone)]
+pub struct ContentLength(pub u64);
+
+impl TypedHeader for ContentLength {
+	const NAME: &'static str = "Content-Length";
+	fn parse(value: &str) -> Result<Self, String> {
+		match value.trim().parse() {
+			Ok(len) => Ok(ContentLength(len)),
+			Err(e) => Err(format!("{}", e)),
+		}
+	}
+}
+
+macro_rules! impl_deref {
+	($($extractor:ident),*) => {
+		$(
+			impl<T> Deref for $extractor<T> {
+				type Target = T;
+				fn deref(&self) -> &T {
+					&self.0
+				}
+			}
+		)*
+	};
+}
+
+impl_deref!(Query, Form, Json, Header);
+
+#[cfg(test)]
+#[derive(serde::Deserialize, Debug, PartialEq)]
+struct TestSearch {
+	term: String,
+	page: Option<u32>,
+}
+
+// returns the message of the 400 Bad Request response if the value couldn't be extracted,
+// or an empty string if it was.
+#[cfg(test)]
+fn invalid_message<T>(result: Result<T, ExtractError>) -> String {
+	match result {
+		Ok(_) => "".to_string(),
+		Err(ExtractError::Invalid(message)) => message,
+		Err(ExtractError::Error(e)) => format!("unexpected error: {}", e),
+	}
+}
+
+#[test]
+fn test_extractors() -> Result<(), Error> {
+	use crate::rustlet_impls::test_request;
+
+	let search =
+		|query: &str| Query::<TestSearch>::from_request(&mut test_request(query, b"", vec![]));
+	assert_eq!(
+		search("term=rust&page=2").map(|q| q.0).ok(),
+		Some(TestSearch {
+			term: "rust".to_string(),
+			page: Some(2)
+		})
+	);
+	assert!(invalid_message(search("page=2")).contains("invalid query"));
+	assert!(invalid_message(search("term=rust&page=x")).contains("invalid query"));
+	let optional = Option::<Query<TestSearch>>::from_request(&mut test_request("", b"", vec![]));
+	assert!(match optional {
+		Ok(None) => true,
+		_ => false,
+	});
+
+	let form = |content_type: &str, content: &[u8]| {
+		Form::<TestSearch>::from_request(&mut test_request(
+			"",
+			content,
+			vec![("Content-Type", content_type)],
+		))
+	};
+	assert_eq!(
+		form(
+			"application/x-www-form-urlencoded; charset=UTF-8",
+			b"term=a+b"
+		)
+		.map(|f| f.0.term)
+		.ok(),
+		Some("a b".to_string())
+	);
+	assert!(
+		invalid_message(form("application/x-www-form-urlencoded", b"page=1"))
+			.contains("invalid form")
+	);
+	assert!(
+		invalid_message(form("application/json", b"term=a")).contains(
+			"expected content type application/x-www-form-urlencoded, found application/json"
+		)
+	);
+
+	let json = |headers: Vec<(&str, &str)>, content: &[u8]| {
+		Json::<TestSearch>::from_request(&mut test_request("", content, headers))
+	};
+	assert_eq!(
+		json(vec![], b"{\"term\":\"rust\"}").map(|j| j.0).ok(),
+		Some(TestSearch {
+			term: "rust".to_string(),
+			page: None
+		})
+	);
+	assert!(invalid_message(json(vec![], b"{\"term\":")).contains("invalid JSON"));
+	assert!(
+		invalid_message(json(vec![("Content-Type", "text/plain")], b"{}"))
+			.contains("expected content type application/json")
+	);
+
+	let header = |name: &str, value: &str| test_request("", b"", vec![(name, value)]);
+	assert_eq!(
+		Header::<UserAgent>::from_request(&mut header("User-Agent", "test/1.0"))
+			.map(|h| h.0 .0)
+			.ok(),
+		Some("test/1.0".to_string())
+	);
+	assert_eq!(
+		invalid_message(Header::<UserAgent>::from_request(&mut header("Host", "a"))),
+		"missing header User-Agent"
+	);
+	for (value, host) in &[
+		("Example.com:8080", "example.com"),
+		("[::1]:8080", "[::1]"),
+		("[::1]", "[::1]"),
+	] {
+		assert_eq!(
+			Header::<Host>::from_request(&mut header("Host", value))
+				.map(|h| h.0 .0)
+				.ok(),
+			Some(host.to_string())
+		);
+	}
+	assert!(
+		invalid_message(Header::<Host>::from_request(&mut header("Host", ":80")))
+			.contains("empty host")
+	);
+	assert!(
+		invalid_message(Header::<ContentLength>::from_request(&mut header(
+			"Content-Length",
+			"-1"
+		)))
+		.starts_with("invalid header Content-Length")
+	);
+	Ok(())
+}
+
+#[test]
+fn test_extract_or_reject() -> Result<(), Error> {
+	use crate::rustlet_impls::http_get;
+	use crate::{HttpConfig, RustletConfig, RustletContainer};
+	use std::time::Duration;
+
+	let port = 18_750;
+	let root_dir = format!(
+		"{}/rustlet_extract_{}",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	let container = RustletContainer::builder()
+		.config(RustletConfig {
+			http_config: HttpConfig {
+				host: "127.0.0.1".to_string(),
+				port,
+				root_dir: root_dir.clone(),
+				..HttpConfig::default()
+			},
+			..RustletConfig::default()
+		})
+		.rustlet("search", |request, response| {
+			match extract_or_reject::<Query<TestSearch>>(request, response)? {
+				Some(Query(search)) => response.write(format!("term={}", search.term).as_bytes()),
+				None => Ok(()),
+			}
+		})
+		.mapping("/search", "search")
+		.build()?;
+
+	let responses = (|| -> Result<Vec<String>, Error> {
+		Ok(vec![
+			http_get(port, "/search?term=rust")?,
+			http_get(port, "/search?page=1")?,
+		])
+	})();
+	container.stop(Duration::from_secs(5))?;
+	let _ = std::fs::remove_dir_all(&root_dir);
+
+	let responses = responses?;
+	assert!(responses[0].contains("term=rust"), "{}", responses[0]);
+	assert!(responses[1].starts_with("HTTP/1.1 400"), "{}", responses[1]);
+	assert!(responses[1].contains("400 Bad Request: invalid query"));
+	assert!(!responses[1].contains("term="));
+	Ok(())
+}
@@ -12,16 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+pub mod auth;
+pub mod cors;
+pub mod csrf;
+pub mod declaration;
+pub mod extract;
 pub mod macros;
+pub mod plugin;
+pub mod ratelimit;
 pub mod rustlet_impls;
+pub mod ser;
 
+#[doc(hidden)]
+pub use inventory;
 pub use nioruntime_http;
 pub use nioruntime_log;
 pub use rustlet_impls::{
-	HttpConfig, RustletAsyncContext, RustletConfig, RustletContainer, RustletRequest,
-	RustletResponse,
+	html_escape, FilterAction, FilterPhase, HttpConfig, RustletAsyncContext, RustletConfig,
+	RustletContainer, RustletContainerBuilder, RustletError, RustletRequest, RustletResponse,
+	SessionString,
 };
 
 pub use nioruntime_err::{Error, ErrorKind};
 pub use nioruntime_evh::{EventHandlerConfig, TlsConfig};
 pub use nioruntime_util::ser::{Readable, Reader, Writeable, Writer};
+
+// derive macros for Readable and Writeable, see ser
+pub use librustlet_macros::{Readable, Writeable};
@@ -44,8 +44,8 @@ lazy_static! {
 ///
 /// debug!();
 ///
-/// // implement a simple `Writeable` and `Readable` struct to demonstrate how the session works.
-/// #[derive(Debug)]
+/// // a simple `Writeable` and `Readable` struct to demonstrate how the session works.
+/// #[derive(Debug, Readable, Writeable)]
 /// struct Example {
 ///    num: u32,
 /// }
@@ -56,20 +56,6 @@ lazy_static! {
 ///     }
 /// }
 ///
-/// impl Writeable for Example {
-///     fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
-///         writer.write_u32(self.num)?;
-///         Ok(())
-///     }
-/// }
-///
-/// impl Readable for Example {
-///     fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
-///         let num = reader.read_u32()?;
-///         Ok(Example { num })
-///     }
-/// }
-///
 /// fn test() -> Result<(), Error> {
 ///
 ///     // init the rustlet container, in this case with default values
@@ -151,7 +137,8 @@ macro_rules! session_delete {
 /// default, this amount of time is 30 minutes, but it is configurable in
 /// [`crate::RustletConfig::session_timeout`]. If only one parameter is specified, the value is retrieved
 /// from the session data store, if two parameters are specified, the value is set, see the examples below
-/// for more details.
+/// for more details. Values implement [`crate::Readable`] and [`crate::Writeable`], which may be
+/// derived, see [`crate::ser`].
 ///
 /// # Examples
 /// ```
@@ -161,8 +148,8 @@ macro_rules! session_delete {
 ///
 /// debug!();
 ///
-/// // implement a simple `Writeable` and `Readable` struct to demonstrate how the session works.
-/// #[derive(Debug)]
+/// // a simple `Writeable` and `Readable` struct to demonstrate how the session works.
+/// #[derive(Debug, Readable, Writeable)]
 /// struct Example {
 ///    num: u32,
 /// }
@@ -173,20 +160,6 @@ macro_rules! session_delete {
 ///     }
 /// }
 ///
-/// impl Writeable for Example {
-///     fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
-///         writer.write_u32(self.num)?;
-///         Ok(())
-///     }
-/// }
-///
-/// impl Readable for Example {
-///     fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
-///         let num = reader.read_u32()?;
-///         Ok(Example { num })
-///     }  
-/// }
-///
 /// fn test() -> Result<(), Error> {
 ///
 ///     // init the rustlet container, in this case with default values
@@ -263,6 +236,189 @@ macro_rules! session {
 	};
 }
 
+/// Sets or gets an attribute of the current request. Attributes are values of any type that
+/// live only as long as the request. They may be used to pass data between filters and
+/// rustlets, for instance an authentication filter may store the user that it authenticated.
+/// The rustlets executed by the tags of an RSP all share the attributes of the request, so a
+/// rustlet may pass data to the rustlets of later tags. If only one parameter is specified, the
+/// value is retrieved as an [`Option`]. The type is coerced by the variable that the value is
+/// assigned to and must implement [`Clone`]. If the attribute does not exist or has a different
+/// type, None is returned. If two parameters are specified, the value is set.
+///
+/// Attributes of type [`String`] may also be displayed in RSPs via expression tags such as
+/// `<@:attr.user>`.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet_filter!("user", "/", |request, _response, phase| {
+///         if phase == FilterPhase::Before {
+///             request.set_attr("user", "admin".to_string())?;
+///         }
+///         Ok(FilterAction::Continue)
+///     });
+///
+///     rustlet!("set_attr", {
+///         request_attr!("visits", 1u64);
+///     });
+///
+///     rustlet!("get_attr", {
+///         let user: Option<String> = request_attr!("user");
+///         let visits: Option<u64> = request_attr!("visits");
+///         response!("user={:?},visits={:?}", user, visits);
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! request_attr {
+	($a:expr) => {
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut *(f.borrow_mut()) {
+			Some((request, _response)) => match request.get_attr($a) {
+				Ok(value) => value,
+				Err(e) => {
+					mainlogerror!("get_attr generated error: {}", e.to_string());
+					None
+				}
+			},
+			None => {
+				mainlogerror!("Error: not in a rustlet context");
+				None
+			}
+		})
+	};
+	($a:expr,$b:expr) => {
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut *(f.borrow_mut()) {
+			Some((request, _response)) => match request.set_attr($a, $b) {
+				Ok(_) => {}
+				Err(e) => {
+					mainlogerror!("set_attr generated error: {}", e.to_string());
+				}
+			},
+			None => {
+				mainlogerror!("Error: not in a rustlet context");
+			}
+		})
+	};
+}
+
+/// Returns the principal of the current request as an [`Option`]. The principal is the user
+/// that was authenticated by HTTP Basic or Bearer authentication. If the request was not
+/// authenticated, None is returned. See [`crate::auth`] for details on authentication.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use librustlet::auth::*;
+/// use nioruntime_log::*;
+/// use std::sync::Arc;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // require a valid HS256 JWT for all uris starting with /api/
+///     rustlet_init!(RustletConfig {
+///         auth: vec![AuthConfig {
+///             pattern: "/api/".to_string(),
+///             realm: "api".to_string(),
+///             scheme: AuthScheme::Bearer(Arc::new(JwtHmacValidator::new(b"secret"))),
+///         }],
+///         ..RustletConfig::default()
+///     });
+///
+///     rustlet!("whoami", {
+///         response!("principal={:?}", principal!());
+///     });
+///
+///     rustlet_mapping!("/api/whoami", "whoami");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! principal {
+	() => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+			Some((request, _response)) => match request.get_principal() {
+				Ok(principal) => principal,
+				Err(e) => {
+					mainlogerror!("principal error: {}", e);
+					None
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				None
+			}
+		})
+	}};
+}
+
+/// Returns the CSRF token of the current session as a [`String`]. The token is created and
+/// stored in the session the first time it is requested. Forms that are protected by the CSRF
+/// filter must include it in the `csrf_token` parameter. In RSPs, the `<@csrf>` tag may be
+/// used to include the hidden input. See [`crate::csrf`] for details.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use librustlet::csrf::CsrfConfig;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // protect all uris from cross-site request forgery
+///     rustlet_init!(RustletConfig {
+///         csrf: Some(CsrfConfig::default()),
+///         ..RustletConfig::default()
+///     });
+///
+///     rustlet!("form", {
+///         response!("<form method=\"post\" action=\"/submit\">");
+///         response!("<input type=\"hidden\" name=\"csrf_token\" value=\"{}\">", csrf_token!());
+///         response!("<input type=\"submit\"></form>");
+///     });
+///
+///     rustlet_mapping!("/form", "form");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! csrf_token {
+	() => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut (*f.borrow_mut()) {
+			Some((request, _response)) => match request.get_csrf_token() {
+				Ok(token) => token,
+				Err(e) => {
+					mainlogerror!("csrf_token error: {}", e);
+					"".to_string()
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				"".to_string()
+			}
+		})
+	}};
+}
+
 /// Flushes any buffered data previously sent via the [`response`] macro.
 ///
 /// # Examples
@@ -456,21 +612,42 @@ macro_rules! async_context {
 /// * [`async_context`]
 /// * [`bin_write`]
 /// * [`cookie`]
+/// * [`csrf_token`]
+/// * [`crate::declaration::rustlet`]
 /// * [`flush`]
 /// * [`header_len`]
 /// * [`header_name`]
 /// * [`header_value`]
+/// * [`html_escape`]
+/// * [`principal`]
 /// * [`query`]
 /// * [`request`]
+/// * [`request_attr`]
 /// * [`request_content`]
 /// * [`response`]
+/// * [`response_escaped`]
+/// * [`rsp_condition`]
+/// * [`rsp_loop`]
+/// * [`rsp_loop_index`]
+/// * [`rsp_loop_value`]
+/// * [`rustlet_error`]
+/// * [`rustlet_error_handler`]
+/// * [`rustlet_filter`]
 /// * [`rustlet_init`]
 /// * [`rustlet_mapping`]
+/// * [`rustlet_not_found`]
+/// * [`rustlet_plugin`]
+/// * [`rustlet_remove`]
+/// * [`rustlet_remove_mapping`]
+/// * [`rustlet_shutdown`]
+/// * [`rustlet_swap_mapping`]
 /// * [`session`]
 /// * [`session_delete`]
 /// * [`set_content_type`]
 /// * [`set_cookie`]
 /// * [`set_redirect`]
+/// * [`set_status`]
+/// * [`tag_attr`]
 ///
 /// # Examples
 /// ```
@@ -557,7 +734,8 @@ macro_rules! rustlet {
 
 /// Initialize the rustlet container based on the specified configuration. The default
 /// configuration may be used by calling `RustletConfig::default()`. See [`crate::RustletConfig`]
-/// for details on configuring the Rustlet and Http containers.
+/// for details on configuring the Rustlet and Http containers. The rustlets declared with the
+/// [`crate::declaration::rustlet`] attribute are added and mapped once the container is started.
 ///
 /// # Examples
 /// ```
@@ -620,7 +798,18 @@ macro_rules! rustlet_init {
 					Ok(_) => {
 						let res = container.start();
 						match res {
-							Ok(_) => {}
+							Ok(_) => match container.add_declared_rustlets() {
+								Ok(_) => {}
+								Err(e) => {
+									const MAIN_LOG: &str = "mainlog";
+									nioruntime_log::log_multi!(
+										nioruntime_log::ERROR,
+										MAIN_LOG,
+										"Couldn't start rustlet: add_declared_rustlets: {}",
+										e.to_string()
+									);
+								}
+							},
 							Err(e) => {
 								const MAIN_LOG: &str = "mainlog";
 								nioruntime_log::log_multi!(
@@ -656,8 +845,131 @@ macro_rules! rustlet_init {
 	}};
 }
 
+/// Declares a plugin, see [`crate::plugin`]. The parameter is a function that registers the
+/// rustlets and mappings of the plugin with the [`crate::plugin::PluginRegistrar`]. The
+/// crate of the plugin must be of type `cdylib` and the resulting library is copied to the
+/// `plugins` directory of the root directory of the server.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use librustlet::plugin::PluginRegistrar;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn register(registrar: &mut PluginRegistrar) -> Result<(), Error> {
+///     registrar.rustlet("greeting_v2", |_request, _response| {
+///         response!("Hello from a plugin\n");
+///         Ok(())
+///     });
+///     registrar.mapping("/greeting", "greeting_v2");
+///     Ok(())
+/// }
+///
+/// rustlet_plugin!(register);
+/// ```
+#[macro_export]
+macro_rules! rustlet_plugin {
+	($a:expr) => {
+		#[no_mangle]
+		pub static RUSTLET_PLUGIN: librustlet::plugin::PluginDeclaration =
+			librustlet::plugin::PluginDeclaration {
+				abi_version: librustlet::plugin::PLUGIN_ABI_VERSION,
+				rustc_version: librustlet::plugin::RUSTC_VERSION_C.as_ptr()
+					as *const std::os::raw::c_char,
+				target: librustlet::plugin::TARGET_C.as_ptr() as *const std::os::raw::c_char,
+				librustlet_version: librustlet::plugin::LIBRUSTLET_VERSION_C.as_ptr()
+					as *const std::os::raw::c_char,
+				register: $a,
+			};
+	};
+}
+
+/// Stops the rustlet container gracefully. New requests are rejected with '503 Service
+/// Unavailable' and their connections are closed, and in-flight requests and async rustlets
+/// are given the specified [`std::time::Duration`] to complete (30 seconds if not specified).
+/// Then the HTTP server is stopped, the remaining entries of the request log are written and,
+/// if [`crate::RustletConfig::session_file`] is configured, the sessions are saved to a file
+/// that only the owner can read.
+///
+/// Please note that the HTTP server only closes its listener when it stops, so connections
+/// are still accepted (and answered with 503) while the requests are completing. The request
+/// log is written by a thread of the HTTP server that can't be flushed, so the container
+/// waits for its last write, which takes about 250ms.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///     rustlet_init!(RustletConfig {
+///         session_file: Some("/tmp/sessions.dat".to_string()),
+///         ..RustletConfig::default()
+///     });
+///
+///     rustlet!("hello_world", {
+///         response!("Hello World\n");
+///     });
+///
+///     rustlet_mapping!("/", "hello_world");
+///
+///     // ... serve requests until it's time to stop
+///
+///     rustlet_shutdown!(std::time::Duration::from_secs(10));
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rustlet_shutdown {
+	() => {{
+		rustlet_shutdown!(std::time::Duration::from_secs(30))
+	}};
+	($timeout:expr) => {{
+		// the lock is released before waiting, as in-flight rustlets may need it to complete
+		let container = match librustlet::macros::RUSTLET_CONTAINER.read() {
+			Ok(container) => Some(container.clone()),
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't stop rustlet container: couldn't get lock: {}",
+					e.to_string()
+				);
+				None
+			}
+		};
+
+		match container {
+			Some(container) => match container.stop($timeout) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't stop rustlet container: {}",
+						e.to_string()
+					);
+				}
+			},
+			None => {}
+		}
+	}};
+}
+
 /// Maps the specified uri to a rustlet. All requests to the container for this uri
-/// will be processed by the specified rustlet.
+/// will be processed by the specified rustlet. If a host is specified, the mapping only
+/// applies to requests whose Host header matches it. Mappings of the requested host take
+/// precedence over mappings without a host. Please note that all hosts share the TLS
+/// certificate of the server, as selecting certificates via SNI is not supported.
 ///
 /// # Examples
 /// ```
@@ -682,11 +994,43 @@ macro_rules! rustlet_init {
 ///     });
 ///     // maps the uri /hello to the rustlet "hello_world"
 ///     rustlet_mapping!("/hello", "hello_world");
+///     rustlet!("hello_api", {
+///         response!("Hello API\n");
+///     });
+///     // requests for api.example.com/hello are processed by "hello_api" instead
+///     rustlet_mapping!(host = "api.example.com", "/hello", "hello_api");
 ///     Ok(())
 /// }
 /// ```
 #[macro_export]
 macro_rules! rustlet_mapping {
+	(host = $h:expr, $a:expr, $b:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.add_host_rustlet_mapping($h, $a, $b) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't start rustlet: add_mapping: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't start rustlet: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
 	($a:expr, $b:expr) => {{
 		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
 
@@ -716,7 +1060,9 @@ macro_rules! rustlet_mapping {
 	}};
 }
 
-/// Sets the content-type header of this request.
+/// Removes a rustlet and the mappings to it from the container. Requests for these uris
+/// receive a '404 Not Found' response. Also see [`rustlet_remove_mapping`].
+///
 /// # Examples
 /// ```
 /// use nioruntime_err::Error;
@@ -726,36 +1072,574 @@ macro_rules! rustlet_mapping {
 /// debug!();
 ///
 /// fn test() -> Result<(), Error> {
-///
-///     // init the rustlet container, in this case with default values
 ///     rustlet_init!(RustletConfig::default());
-///
-///     rustlet!("set_content_type", {
-///         set_content_type!("text/html");
-///         response!("<html><body><strong>Some Content Here</strong></body></html>");
+///     rustlet!("beta_feature", {
+///         response!("beta\n");
 ///     });
+///     rustlet_mapping!("/beta", "beta_feature");
 ///
-///     rustlet_mapping!("/", "set_content_type");
-///
+///     // later, disable the feature. This also removes the mapping of "/beta".
+///     rustlet_remove!("beta_feature");
 ///     Ok(())
 /// }
 /// ```
 #[macro_export]
-macro_rules! set_content_type {
-	($a:expr) => {
-		add_header!("Content-Type", $a);
-	};
-}
+macro_rules! rustlet_remove {
+	($a:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
 
-/// Adds a header to the response for this rustlet. The first parameter is the name of the header
-/// to set and the second parameter is the value of the header. See examples below.
-/// # Examples
-/// ```
-/// use nioruntime_err::Error;
-/// use librustlet::*;
-/// use nioruntime_log::*;
-///
-/// debug!();
+		match container {
+			Ok(mut container) => match container.remove_rustlet($a) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't remove rustlet: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't remove rustlet: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Removes the mapping of the specified uri. If a host is specified, the mapping of that
+/// host is removed. See [`rustlet_mapping`] and [`rustlet_remove`] for an example.
+#[macro_export]
+macro_rules! rustlet_remove_mapping {
+	(host = $h:expr, $a:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.remove_host_rustlet_mapping($h, $a) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't remove mapping: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't remove mapping: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+	($a:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.remove_rustlet_mapping($a) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't remove mapping: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't remove mapping: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Maps an already mapped uri to another rustlet, for example to switch between two versions
+/// of a handler without restarting. Each request is processed by either the old or the new
+/// rustlet. If a host is specified, the mapping of that host is swapped. The swap fails and
+/// an error is logged if the uri is not mapped or the rustlet does not exist.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///     rustlet_init!(RustletConfig::default());
+///     rustlet!("checkout_blue", {
+///         response!("blue\n");
+///     });
+///     rustlet!("checkout_green", {
+///         response!("green\n");
+///     });
+///     rustlet_mapping!("/checkout", "checkout_blue");
+///
+///     // switch to the green version
+///     rustlet_swap_mapping!("/checkout", "checkout_green");
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rustlet_swap_mapping {
+	(host = $h:expr, $a:expr, $b:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.swap_host_rustlet_mapping($h, $a, $b) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't swap mapping: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't swap mapping: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+	($a:expr, $b:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.swap_rustlet_mapping($a, $b) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't swap mapping: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't swap mapping: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Adds a filter to the rustlet container. Filters are closures that are called before and
+/// after the rustlet that a request is mapped to (or the RSP that is requested) is executed.
+/// The first parameter is the name of the filter. Adding a filter with the name of an existing
+/// filter replaces it. The second parameter is the pattern of uris that the filter applies to.
+/// A pattern that contains '*' or '?' is a glob that must match the entire uri, where '*'
+/// matches any sequence of characters and '?' matches any single character. Any other pattern
+/// matches the uri itself and all uris below it, so "/admin" matches "/admin" and "/admin/users"
+/// but not "/administrator". The third parameter is the closure. It is called with
+/// the request, the response and the [`crate::FilterPhase`] and returns a
+/// [`crate::FilterAction`].
+///
+/// In the [`crate::FilterPhase::Before`] phase, a filter may modify the request or write its own
+/// response and return [`crate::FilterAction::Stop`], in which case the rustlet and the
+/// remaining filters are not executed. In the [`crate::FilterPhase::After`] phase, a filter may
+/// add headers to the response, provided that the rustlet did not flush it.
+///
+/// Filters are executed in the order that they were added in the before phase and in the
+/// reverse order in the after phase. Only the filters that were executed in the before phase
+/// are executed in the after phase. The request and response macros may be used in filters.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("hello", {
+///         response!("hello world!");
+///     });
+///
+///     // only allow requests to /api/* that have an api key
+///     rustlet_filter!("api_key", "/api/*", |request, response, phase| {
+///         if phase == FilterPhase::Before && request.get_header("X-Api-Key")?.is_none() {
+///             response.set_status(403)?;
+///             response.write("forbidden".as_bytes())?;
+///             return Ok(FilterAction::Stop);
+///         }
+///         Ok(FilterAction::Continue)
+///     });
+///
+///     // add a header to every response
+///     rustlet_filter!("no_cache", "/", |_request, _response, phase| {
+///         if phase == FilterPhase::After {
+///             add_header!("Cache-Control", "no-cache");
+///         }
+///         Ok(FilterAction::Continue)
+///     });
+///
+///     rustlet_mapping!("/api/hello", "hello");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rustlet_filter {
+	($a:expr, $b:expr, $c:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+		match container {
+			Ok(mut container) => {
+				let res = container.add_filter($a, $b, $c);
+				match res {
+					Ok(_) => {}
+					Err(e) => {
+						const MAIN_LOG: &str = "mainlog";
+						nioruntime_log::log_multi!(
+							nioruntime_log::ERROR,
+							MAIN_LOG,
+							"Error adding filter to container: {}",
+							e.to_string()
+						);
+					}
+				}
+			}
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't add filter: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Registers the rustlet that generates the content of error responses. The error handler is
+/// executed whenever processing a request results in an error. The handler may retrieve the
+/// error, including its [`nioruntime_err::ErrorKind`], the uri and the status of the response,
+/// via the [`rustlet_error`] macro and may choose its output format, for instance based on the
+/// Accept header. If [`nioruntime_http::HttpConfig::debug`] is true, the details of the error,
+/// including its cause and backtrace, are also available. If the headers of the response were
+/// already written when the error occurred, the output of the handler is appended to the
+/// response.
+///
+/// If no error handler is registered, a default HTML or JSON response is returned, based on the
+/// Accept header of the request.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::{Error, ErrorKind};
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("error_handler", {
+///         let error = rustlet_error!().unwrap();
+///         let message = match error.kind() {
+///             ErrorKind::InvalidRSPError(_) => "invalid page".to_string(),
+///             _ => "internal error".to_string(),
+///         };
+///         if request!("header", "Accept").contains("application/json") {
+///             set_content_type!("application/json");
+///             response!("{{\"status\": {}, \"error\": \"{}\"}}", error.status(), message);
+///         } else {
+///             set_content_type!("text/html");
+///             response!("<html><body>{}: {}", error.status(), message);
+///             match error.details() {
+///                 Some(details) => response_escaped!("<pre>{}</pre>", details),
+///                 None => {}
+///             }
+///             response!("</body></html>");
+///         }
+///     });
+///
+///     rustlet_error_handler!("error_handler");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rustlet_error_handler {
+	($a:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.set_error_handler($a) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't set error handler: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't set error handler: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Returns the [`crate::RustletError`] that is being handled as an [`Option`]. The error is
+/// only available to the rustlet registered via [`rustlet_error_handler`]. In all other rustlets,
+/// None is returned. See [`rustlet_error_handler`] for an example.
+#[macro_export]
+macro_rules! rustlet_error {
+	() => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+			Some((request, _response)) => match request.get_error() {
+				Ok(error) => error,
+				Err(e) => {
+					mainlogerror!("rustlet_error error: {}", e);
+					None
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				None
+			}
+		})
+	}};
+}
+
+/// Registers the rustlet or RSP that generates the content of '404 Not Found' responses.
+/// The parameter is either the name of a rustlet or the uri of an RSP in the webroot. The
+/// registered rustlet or RSP is executed whenever a request cannot be mapped, for instance
+/// when an RSP does not exist, and the response is returned with a 404 status. If no
+/// rustlet or RSP is registered, a default page is returned.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("not_found", {
+///         set_content_type!("application/json");
+///         response!("{{\"error\": \"'{}' not found\"}}", request!("uri"));
+///     });
+///
+///     rustlet_not_found!("not_found");
+///
+///     // alternatively an RSP may be used
+///     // rustlet_not_found!("/404.rsp");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rustlet_not_found {
+	($a:expr) => {{
+		let mut container = librustlet::macros::RUSTLET_CONTAINER.write();
+
+		match container {
+			Ok(mut container) => match container.set_not_found($a) {
+				Ok(_) => {}
+				Err(e) => {
+					const MAIN_LOG: &str = "mainlog";
+					nioruntime_log::log_multi!(
+						nioruntime_log::ERROR,
+						MAIN_LOG,
+						"Couldn't set not found rustlet: {}",
+						e.to_string()
+					);
+				}
+			},
+			Err(e) => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't set not found rustlet: couldn't get lock: {}",
+					e.to_string()
+				);
+			}
+		}
+	}};
+}
+
+/// Sets the content-type header of this request.
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("set_content_type", {
+///         set_content_type!("text/html");
+///         response!("<html><body><strong>Some Content Here</strong></body></html>");
+///     });
+///
+///     rustlet_mapping!("/", "set_content_type");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_content_type {
+	($a:expr) => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut (*f.borrow_mut()) {
+			Some((request, response)) => {
+				let res = response.set_content_type($a);
+				match res {
+					Ok(_) => {}
+					Err(e) => {
+						const MAIN_LOG: &str = "mainlog";
+						nioruntime_log::log_multi!(
+							nioruntime_log::ERROR,
+							MAIN_LOG,
+							"Couldn't call response.set_content_type: {}",
+							e.to_string()
+						);
+					}
+				}
+			}
+			None => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't find response struct",
+				);
+			}
+		});
+	}};
+}
+
+/// Sets the HTTP status code of the response for this rustlet. The status must be set before
+/// any data is flushed. If no status is set, '200 OK' is returned.
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("set_status", {
+///         set_status!(201);
+///         set_content_type!("application/json");
+///         response!("{\"created\": true}");
+///     });
+///
+///     rustlet_mapping!("/", "set_status");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! set_status {
+	($a:expr) => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut (*f.borrow_mut()) {
+			Some((request, response)) => {
+				let res = response.set_status($a);
+				match res {
+					Ok(_) => {}
+					Err(e) => {
+						const MAIN_LOG: &str = "mainlog";
+						nioruntime_log::log_multi!(
+							nioruntime_log::ERROR,
+							MAIN_LOG,
+							"Couldn't call response.set_status: {}",
+							e.to_string()
+						);
+					}
+				}
+			}
+			None => {
+				const MAIN_LOG: &str = "mainlog";
+				nioruntime_log::log_multi!(
+					nioruntime_log::ERROR,
+					MAIN_LOG,
+					"Couldn't find response struct",
+				);
+			}
+		});
+	}};
+}
+
+/// Adds a header to the response for this rustlet. The first parameter is the name of the header
+/// to set and the second parameter is the value of the header. See examples below.
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
 ///
 /// fn test() -> Result<(), Error> {
 ///
@@ -1002,6 +1886,78 @@ macro_rules! response {
 	};
 }
 
+/// Escapes the specified value for inclusion in an HTML document. The value may be any type
+/// that implements [`std::fmt::Display`]. The characters `&`, `<`, `>`, `"` and `'` are
+/// replaced with their corresponding HTML entities. Also see [`response_escaped`].
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("hello", {
+///         let name = html_escape!(query!("name"));
+///         response!("<html><body>Hello {}!</body></html>", name);
+///     });
+///
+///     rustlet_mapping!("/", "hello");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! html_escape {
+	($a:expr) => {{
+		librustlet::html_escape(&format!("{}", $a))
+	}};
+}
+
+/// Writes a formated response to the client in the same manner as the [`response`] macro,
+/// except that each of the arguments is HTML escaped (see [`html_escape`]) before it is
+/// formatted. The format string itself is not escaped, so it may contain HTML markup. This
+/// should be used whenever user supplied data is included in an HTML response.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("hello", {
+///         // if name is "<script>", "&lt;script&gt;" is written.
+///         response_escaped!("<html><body>Hello {}!</body></html>", query!("name"));
+///     });
+///
+///     rustlet_mapping!("/", "hello");
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! response_escaped {
+	($a:expr) => {
+		response!($a)
+	};
+	($a:expr,$($b:expr),+ $(,)?) => {
+		response!($a, $(html_escape!($b)),+)
+	};
+}
+
 /// Returns the content of the message body of the HTTP request.
 ///
 /// # Examples
@@ -1289,6 +2245,268 @@ macro_rules! query {
 	}};
 }
 
+/// Get the value of the specified attribute of the RSP tag that is executing this rustlet.
+/// RSP tags may pass attributes to the rustlet they execute, which allows the same rustlet
+/// to render differently at different places on a page. For example, the RSP below includes
+/// the "widget" rustlet twice, each time with different attributes:
+///
+/// ```text
+/// <html>
+///     <body>
+///         <@=widget limit="5" title="Latest">
+///         <@=widget limit="10" title='Most "Popular"'>
+///     </body>
+/// </html>
+/// ```
+///
+/// If the attribute was not specified or the rustlet was not executed from an RSP, an empty
+/// string is returned.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("widget", {
+///         let title = tag_attr!("title");
+///         let limit: usize = tag_attr!("limit").parse().unwrap_or(3);
+///         response!("<h2>{}</h2>\n", title);
+///         for i in 0..limit {
+///             response!("<div>item {}</div>\n", i);
+///         }
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! tag_attr {
+	($a:expr) => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+			Some((request, _response)) => match request.get_tag_attr($a) {
+				Ok(attr) => match attr {
+					Some(attr) => attr,
+					None => "".to_string(),
+				},
+				Err(e) => {
+					mainlogerror!("tag_attr error: {}", e);
+					"".to_string()
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				"".to_string()
+			}
+		})
+	}};
+}
+
+/// Sets the condition of an RSP `<@if>` block. The rustlet specified by the `rustlet` attribute
+/// of the `<@if>` tag is executed and if it sets the condition to true, the content between
+/// `<@if>` and `<@else>` (or `<@end>`) is included. Otherwise, the content between `<@else>`
+/// and `<@end>` is included. If the rustlet does not call this macro, the condition is false.
+///
+/// ```text
+/// <@if rustlet="is_logged_in">
+///     Welcome back, <@:session.user>!
+/// <@else>
+///     <a href="/login">Login</a>
+/// <@end>
+/// ```
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("is_logged_in", {
+///         let user: Option<SessionString> = session!("user");
+///         rsp_condition!(user.is_some());
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rsp_condition {
+	($a:expr) => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut (*f.borrow_mut()) {
+			Some((request, _response)) => match request.set_rsp_condition($a) {
+				Ok(_) => {}
+				Err(e) => {
+					mainlogerror!("rsp_condition error: {}", e);
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+			}
+		})
+	}};
+}
+
+/// Sets the items of an RSP `<@for>` block. The rustlet specified by the `rustlet` attribute
+/// of the `<@for>` tag is executed and the content between `<@for>` and `<@end>` is repeated
+/// once for each of the items that it specifies. The parameter may be any collection whose
+/// items implement [`std::fmt::Display`]. Within the block, the value and index of the current
+/// iteration are available to rustlets via [`rsp_loop_value`] and [`rsp_loop_index`] and to
+/// expression tags via `<@:loop.value>` and `<@:loop.index>`.
+///
+/// ```text
+/// <ul>
+/// <@for rustlet="items" limit="10">
+///     <li><@:loop.index>: <@=item_detail></li>
+/// <@end>
+/// </ul>
+/// ```
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("items", {
+///         let limit: usize = tag_attr!("limit").parse().unwrap_or(5);
+///         let items: Vec<usize> = (100..(100 + limit)).collect();
+///         rsp_loop!(items);
+///     });
+///
+///     rustlet!("item_detail", {
+///         response!("item number {}", rsp_loop_value!());
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rsp_loop {
+	($a:expr) => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &mut (*f.borrow_mut()) {
+			Some((request, _response)) => {
+				let items: Vec<String> = $a.into_iter().map(|item| format!("{}", item)).collect();
+				match request.set_rsp_loop_items(items) {
+					Ok(_) => {}
+					Err(e) => {
+						mainlogerror!("rsp_loop error: {}", e);
+					}
+				}
+			}
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+			}
+		})
+	}};
+}
+
+/// Returns the value of the current iteration of the enclosing RSP `<@for>` block. If this
+/// rustlet is not executing within a `<@for>` block, an empty string is returned. See
+/// [`rsp_loop`] for details.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("item_detail", {
+///         response!("item {} is {}", rsp_loop_index!(), rsp_loop_value!());
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rsp_loop_value {
+	() => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+			Some((request, _response)) => match request.get_rsp_loop_value() {
+				Ok(value) => value.unwrap_or("".to_string()),
+				Err(e) => {
+					mainlogerror!("rsp_loop_value error: {}", e);
+					"".to_string()
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				"".to_string()
+			}
+		})
+	}};
+}
+
+/// Returns the zero based index of the current iteration of the enclosing RSP `<@for>` block.
+/// If this rustlet is not executing within a `<@for>` block, 0 is returned. See [`rsp_loop`]
+/// for details.
+///
+/// # Examples
+/// ```
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn test() -> Result<(), Error> {
+///
+///     // init the rustlet container, in this case with default values
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("item_detail", {
+///         response!("item {} is {}", rsp_loop_index!(), rsp_loop_value!());
+///     });
+///
+///     Ok(())
+/// }
+/// ```
+#[macro_export]
+macro_rules! rsp_loop_index {
+	() => {{
+		librustlet::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+			Some((request, _response)) => match request.get_rsp_loop_index() {
+				Ok(index) => index.unwrap_or(0),
+				Err(e) => {
+					mainlogerror!("rsp_loop_index error: {}", e);
+					0
+				}
+			},
+			None => {
+				mainlogerror!("unexpected error no request/response found");
+				0
+			}
+		})
+	}};
+}
+
 /// Get data from the request for this rustlet.
 /// See the example below for possible values of the request parameter.
 ///
@@ -1316,6 +2534,12 @@ macro_rules! query {
 ///         response!("blah (should be empty)='{}'\n", unknown);
 ///         let query = request!("query"); // the full query for the request
 ///         response!("query='{}'\n", query);
+///         let is_tls = request!("is_tls"); // 'true' if the connection uses TLS
+///         response!("is_tls='{}'\n", is_tls);
+///         let connection_id = request!("connection_id"); // the id of the connection
+///         response!("connection_id='{}'\n", connection_id);
+///         let local_port = request!("local_port"); // the port the request was received on
+///         response!("local_port='{}'\n", local_port);
 ///     });
 ///
 ///     rustlet_mapping!("/", "request");
@@ -1353,6 +2577,12 @@ macro_rules! request {
 					format!("{}", request.get_header_len().unwrap_or(0))
 				} else if value == "uri" {
 					request.get_uri().unwrap_or("".to_string())
+				} else if value == "is_tls" {
+					format!("{}", request.is_tls().unwrap_or(false))
+				} else if value == "connection_id" {
+					format!("{}", request.get_connection_id().unwrap_or(0))
+				} else if value == "local_port" {
+					format!("{}", request.get_local_port().unwrap_or(0))
 				} else {
 					mainlogerror!("unknown parameter: '{}'", $a);
 					"".to_string()
@@ -0,0 +1,464 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hot-loading of rustlets from plugins. If [`crate::RustletConfig::plugins`] is set, the
+//! container loads the shared libraries (cdylibs) in the `plugins` directory of the root
+//! directory of the HTTP server when it starts, and checks the directory for changes
+//! periodically. New plugins are loaded, changed plugins are reloaded and the rustlets and
+//! mappings of deleted plugins are removed.
+//!
+//! A plugin is a crate of type `cdylib` that depends on librustlet and declares its rustlets
+//! and mappings via the [`crate::rustlet_plugin`] macro. The macros that read the request
+//! and write the response, such as [`crate::request`] and [`crate::response`], may be used
+//! within the rustlets of a plugin.
+//!
+//! A plugin has its own copy of the statics of librustlet, so the macros that change the
+//! container, such as [`crate::rustlet_mapping`] and [`crate::rustlet_remove`], have no
+//! effect on the container of the server when they are used within a plugin. The rustlets
+//! of a plugin only access the server through the request and the response.
+//!
+//! Rust has no stable ABI, so the librustlet of a plugin must be the same build as the
+//! librustlet of the server: the same version, source and features, built with the same
+//! compiler and for the same target. The declaration that plugins
+//! export starts with fields that only use C types: the [`PLUGIN_ABI_VERSION`], the output of
+//! `rustc --version` ([`RUSTC_VERSION`]), the target triple ([`TARGET`]) and the version of
+//! librustlet. Types with the Rust ABI, such as the register function, are only used once all
+//! of them match the server. Plugins that don't match are refused. The rustlets of a refused
+//! plugin that was loaded before stay in place.
+//!
+//! The names of the rustlets and the paths of the mappings of a plugin must not be used by the
+//! rustlets and mappings of the server or of other plugins. Plugins with such collisions are
+//! refused.
+//!
+//! When a plugin is reloaded, new requests are processed by the new version of its rustlets,
+//! while the requests that the old version is processing complete with it. Libraries are
+//! never unloaded, as the code of a plugin (for example the destructors of its thread local
+//! variables) may still run after its rustlets were replaced, so each version of a plugin
+//! stays in memory until the server stops.
+
+use crate::rustlet_impls::{Rustlet, RustletRequest, RustletResponse};
+use libloading::Library;
+use nioruntime_err::{Error, ErrorKind};
+use std::collections::{HashMap, HashSet};
+use std::ffi::CStr;
+use std::fs::{copy, create_dir_all, metadata, read_dir, remove_file};
+use std::os::raw::c_char;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+
+/// The version of the interface between the container and plugins. It is incremented
+/// whenever this interface changes.
+pub const PLUGIN_ABI_VERSION: u32 = 2;
+/// The version of librustlet.
+pub const LIBRUSTLET_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// The version of the compiler that librustlet was built with, as reported by
+/// `rustc --version`.
+pub const RUSTC_VERSION: &str = env!("RUSTLET_RUSTC_VERSION");
+/// The target triple that librustlet was built for.
+pub const TARGET: &str = env!("RUSTLET_TARGET");
+
+// the nul terminated versions that are used by the rustlet_plugin macro.
+#[doc(hidden)]
+pub const LIBRUSTLET_VERSION_C: &str = concat!(env!("CARGO_PKG_VERSION"), "\0");
+#[doc(hidden)]
+pub const RUSTC_VERSION_C: &str = concat!(env!("RUSTLET_RUSTC_VERSION"), "\0");
+#[doc(hidden)]
+pub const TARGET_C: &str = concat!(env!("RUSTLET_TARGET"), "\0");
+
+// the interval in seconds between checks of the plugins directory.
+const PLUGIN_CHECK_INTERVAL: u64 = 10;
+// the symbol that is exported by the rustlet_plugin macro.
+const PLUGIN_SYMBOL: &[u8] = b"RUSTLET_PLUGIN\0";
+const DLL_EXTENSION: &str = std::env::consts::DLL_EXTENSION;
+
+// the number of plugins loaded by the process. Each copy of a plugin gets its own file, as a
+// loaded library must never be overwritten, even if containers share the plugins directory.
+static LOAD_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// The declaration that is exported by plugins. It is created by the
+/// [`crate::rustlet_plugin`] macro. All fields before `register` only use C types, so that
+/// they can be checked before any type with the Rust ABI is used.
+#[repr(C)]
+pub struct PluginDeclaration {
+	/// The [`PLUGIN_ABI_VERSION`] that the plugin was built with. It must be the first field,
+	/// so that it can be checked before anything else is read.
+	pub abi_version: u32,
+	/// The nul terminated [`RUSTC_VERSION`] that the plugin was built with.
+	pub rustc_version: *const c_char,
+	/// The nul terminated [`TARGET`] that the plugin was built for.
+	pub target: *const c_char,
+	/// The nul terminated [`LIBRUSTLET_VERSION`] that the plugin was built with.
+	pub librustlet_version: *const c_char,
+	/// The function that registers the rustlets and mappings of the plugin. It is only called
+	/// if all of the versions above match the server.
+	pub register: fn(&mut PluginRegistrar) -> Result<(), Error>,
+}
+
+// the declaration only points to constants of the plugin.
+unsafe impl Sync for PluginDeclaration {}
+
+/// The rustlets and mappings of a plugin. See [`crate::rustlet_plugin`].
+pub struct PluginRegistrar {
+	rustlets: Vec<(String, Rustlet)>,
+	mappings: Vec<(String, String)>,
+}
+
+impl PluginRegistrar {
+	fn new() -> Self {
+		PluginRegistrar {
+			rustlets: vec![],
+			mappings: vec![],
+		}
+	}
+
+	/// Add a rustlet with the specified name. Rustlet names must be unique across the
+	/// container, so the name should be specific to the plugin.
+	pub fn rustlet<F>(&mut self, name: &str, rustlet: F)
+	where
+		F: Fn(&mut RustletRequest, &mut RustletResponse) -> Result<(), Error>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		// this is compiled into the plugin, so it sets the request and response that the
+		// macros of the plugin use.
+		let rustlet: Rustlet = Box::pin(
+			move |request: &mut RustletRequest, response: &mut RustletResponse| {
+				crate::macros::LOCALRUSTLET.with(|f| {
+					*f.borrow_mut() = Some(((*request).clone(), (*response).clone()));
+				});
+				(rustlet)(request, response)
+			},
+		);
+		self.rustlets.push((name.to_string(), rustlet));
+	}
+
+	/// Map the specified path to a rustlet.
+	pub fn mapping(&mut self, path: &str, name: &str) {
+		self.mappings.push((path.to_string(), name.to_string()));
+	}
+}
+
+// a loaded plugin.
+struct LoadedPlugin {
+	modified: Option<SystemTime>,
+	rustlets: Vec<String>,
+	mappings: Vec<String>,
+}
+
+// the changes to apply to the container after the plugins directory was checked.
+pub(crate) struct PluginChanges {
+	pub(crate) add_rustlets: Vec<(String, Rustlet)>,
+	pub(crate) add_mappings: Vec<(String, String)>,
+	pub(crate) remove_rustlets: Vec<String>,
+	pub(crate) remove_mappings: Vec<String>,
+}
+
+impl PluginChanges {
+	fn new() -> Self {
+		PluginChanges {
+			add_rustlets: vec![],
+			add_mappings: vec![],
+			remove_rustlets: vec![],
+			remove_mappings: vec![],
+		}
+	}
+}
+
+// the plugins of a container.
+pub(crate) struct Plugins {
+	dir: String,
+	last_check: Option<Instant>,
+	loaded: HashMap<String, LoadedPlugin>,
+}
+
+impl Plugins {
+	pub(crate) fn new(root_dir: &str) -> Self {
+		Plugins {
+			dir: format!("{}/plugins", root_dir),
+			last_check: None,
+			loaded: HashMap::new(),
+		}
+	}
+
+	// check the plugins directory for new, changed and deleted plugins. Returns the changes
+	// to apply to the container and the errors of plugins that couldn't be loaded. The
+	// rustlets and mappings are those of the container, including the ones of plugins. Unless
+	// force is set, the directory is checked at most every PLUGIN_CHECK_INTERVAL seconds.
+	pub(crate) fn check(
+		&mut self,
+		force: bool,
+		rustlets: &HashSet<String>,
+		mappings: &HashSet<String>,
+	) -> Result<(PluginChanges, Vec<String>), Error> {
+		let mut changes = PluginChanges::new();
+		let mut errors = vec![];
+		match self.last_check {
+			Some(last_check) if !force => {
+				if last_check.elapsed().as_secs() < PLUGIN_CHECK_INTERVAL {
+					return Ok((changes, errors));
+				}
+			}
+			None => self.remove_stale_copies(),
+			_ => {}
+		}
+		self.last_check = Some(Instant::now());
+
+		// the rustlets and mappings that don't belong to a plugin
+		let core_rustlets: HashSet<&String> = rustlets
+			.iter()
+			.filter(|name| !self.loaded.values().any(|p| p.rustlets.contains(name)))
+			.collect();
+		let core_mappings: HashSet<&String> = mappings
+			.iter()
+			.filter(|path| !self.loaded.values().any(|p| p.mappings.contains(path)))
+			.collect();
+
+		create_dir_all(&self.dir)?;
+		let mut files = HashMap::new();
+		for entry in read_dir(&self.dir)? {
+			let path = entry?.path();
+			if path.is_file()
+				&& path.extension().map(|ext| ext.to_str()) == Some(Some(DLL_EXTENSION))
+			{
+				let modified = metadata(&path).ok().and_then(|md| md.modified().ok());
+				files.insert(path.display().to_string(), modified);
+			}
+		}
+
+		// deleted plugins
+		let deleted: Vec<String> = self
+			.loaded
+			.keys()
+			.filter(|file| !files.contains_key(*file))
+			.cloned()
+			.collect();
+		for file in deleted {
+			match self.loaded.remove(&file) {
+				Some(plugin) => {
+					changes.remove_mappings.extend(plugin.mappings);
+					changes.remove_rustlets.extend(plugin.rustlets);
+				}
+				None => {}
+			}
+		}
+
+		// new and changed plugins
+		let mut files: Vec<(String, Option<SystemTime>)> = files.into_iter().collect();
+		files.sort();
+		for (file, modified) in files {
+			match self.loaded.get(&file) {
+				Some(plugin) if plugin.modified == modified => continue,
+				_ => {}
+			}
+
+			let registrar = match self.load(&file).and_then(|registrar| {
+				self.check_collisions(&file, &registrar, &core_rustlets, &core_mappings)?;
+				Ok(registrar)
+			}) {
+				Ok(registrar) => registrar,
+				Err(e) => {
+					errors.push(format!("couldn't load plugin '{}': {}", file, e));
+					// don't retry until the plugin changes again
+					match self.loaded.get_mut(&file) {
+						Some(plugin) => plugin.modified = modified,
+						None => {}
+					}
+					continue;
+				}
+			};
+
+			let rustlets: Vec<String> = registrar
+				.rustlets
+				.iter()
+				.map(|(name, _)| name.clone())
+				.collect();
+			let mappings: Vec<String> = registrar
+				.mappings
+				.iter()
+				.map(|(path, _)| path.clone())
+				.collect();
+			match self.loaded.remove(&file) {
+				Some(plugin) => {
+					changes.remove_mappings.extend(
+						plugin
+							.mappings
+							.into_iter()
+							.filter(|path| !mappings.contains(path)),
+					);
+					changes.remove_rustlets.extend(
+						plugin
+							.rustlets
+							.into_iter()
+							.filter(|name| !rustlets.contains(name)),
+					);
+				}
+				None => {}
+			}
+
+			changes.add_rustlets.extend(registrar.rustlets);
+			changes.add_mappings.extend(registrar.mappings);
+			self.loaded.insert(
+				file,
+				LoadedPlugin {
+					modified,
+					rustlets,
+					mappings,
+				},
+			);
+		}
+
+		Ok((changes, errors))
+	}
+
+	// returns an error if a rustlet or mapping of the plugin in the specified file is used by
+	// the container or by another plugin.
+	fn check_collisions(
+		&self,
+		file: &str,
+		registrar: &PluginRegistrar,
+		core_rustlets: &HashSet<&String>,
+		core_mappings: &HashSet<&String>,
+	) -> Result<(), Error> {
+		for (name, _) in &registrar.rustlets {
+			if core_rustlets.contains(name) {
+				return Err(ErrorKind::SetupError(format!(
+					"rustlet '{}' already exists in the container",
+					name
+				))
+				.into());
+			}
+			match self
+				.loaded
+				.iter()
+				.find(|(other, plugin)| *other != file && plugin.rustlets.contains(name))
+			{
+				Some((other, _)) => {
+					return Err(ErrorKind::SetupError(format!(
+						"rustlet '{}' already exists in plugin '{}'",
+						name, other
+					))
+					.into())
+				}
+				None => {}
+			}
+		}
+		for (path, _) in &registrar.mappings {
+			if core_mappings.contains(path) {
+				return Err(ErrorKind::SetupError(format!(
+					"'{}' is already mapped by the container",
+					path
+				))
+				.into());
+			}
+			match self
+				.loaded
+				.iter()
+				.find(|(other, plugin)| *other != file && plugin.mappings.contains(path))
+			{
+				Some((other, _)) => {
+					return Err(ErrorKind::SetupError(format!(
+						"'{}' is already mapped by plugin '{}'",
+						path, other
+					))
+					.into())
+				}
+				None => {}
+			}
+		}
+		Ok(())
+	}
+
+	// remove the copies of plugins that were loaded by previous runs of the server.
+	fn remove_stale_copies(&self) {
+		let prefix = format!("{}.", std::process::id());
+		match read_dir(format!("{}/.loaded", self.dir)) {
+			Ok(entries) => {
+				for entry in entries.flatten() {
+					if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+						let _ = remove_file(entry.path());
+					}
+				}
+			}
+			Err(_) => {}
+		}
+	}
+
+	// load the specified plugin and call its register function. The plugin is loaded from a
+	// copy, as a library that is loaded again from the same path may not be reloaded. The
+	// library is never unloaded, see the module documentation.
+	fn load(&self, file: &str) -> Result<PluginRegistrar, Error> {
+		let loaded_dir = format!("{}/.loaded", self.dir);
+		create_dir_all(&loaded_dir)?;
+		let path = format!(
+			"{}/{}.{}.{}",
+			loaded_dir,
+			std::process::id(),
+			LOAD_COUNT.fetch_add(1, Ordering::SeqCst),
+			DLL_EXTENSION
+		);
+		copy(file, &path)?;
+
+		let library = unsafe { Library::new(&path) }.map_err(|e| {
+			let _ = remove_file(&path);
+			ErrorKind::SetupError(format!("{}", e))
+		})?;
+		let declaration = unsafe {
+			library
+				.get::<*const PluginDeclaration>(PLUGIN_SYMBOL)
+				.map(|symbol| *symbol)
+		};
+		// the code of the plugin must stay in place even if it is refused, as its static
+		// initializers may have registered destructors
+		std::mem::forget(library);
+		let declaration = declaration.map_err(|e| {
+			ErrorKind::SetupError(format!("not a plugin, use the rustlet_plugin macro: {}", e))
+		})?;
+
+		// the ABI version is the first field and must be checked before anything else
+		let abi_version = unsafe { (*declaration).abi_version };
+		if abi_version != PLUGIN_ABI_VERSION {
+			return Err(ErrorKind::SetupError(format!(
+				"the plugin ABI version is {}, but {} is required",
+				abi_version, PLUGIN_ABI_VERSION
+			))
+			.into());
+		}
+		let declaration = unsafe { &*declaration };
+		for (name, value, expected) in [
+			("compiler", declaration.rustc_version, RUSTC_VERSION),
+			("target", declaration.target, TARGET),
+			(
+				"librustlet",
+				declaration.librustlet_version,
+				LIBRUSTLET_VERSION,
+			),
+		]
+		.iter()
+		{
+			let value = unsafe { CStr::from_ptr(*value) }.to_string_lossy();
+			if value != *expected {
+				return Err(ErrorKind::SetupError(format!(
+					"the plugin was built with {} '{}', but '{}' is required",
+					name, value, expected
+				))
+				.into());
+			}
+		}
+
+		let mut registrar = PluginRegistrar::new();
+		(declaration.register)(&mut registrar)?;
+		Ok(registrar)
+	}
+}
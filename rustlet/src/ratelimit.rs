@@ -0,0 +1,443 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rate limiting and concurrency limits. Rate limits are configured via
+//! [`crate::RustletConfig::rate_limits`]. Each [`RateLimitConfig`] applies a token bucket per
+//! client to the uris that match its pattern. Requests that exceed the limit receive a
+//! '429 Too Many Requests' response with a Retry-After header.
+//!
+//! The maximum number of concurrent executions of a rustlet is configured via
+//! [`crate::RustletConfig::max_concurrency`]. Requests that exceed it receive a
+//! '503 Service Unavailable' response, so that slow rustlets cannot occupy all threads.
+//!
+//! The number of requests rejected by each limit is written to the stats log.
+
+use crate::rustlet_impls::{FilterAction, FilterPhase, RustletRequest, RustletResponse};
+use nioruntime_err::{Error, ErrorKind};
+use nioruntime_log::*;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+info!();
+
+const STATS_LOG: &str = "statslog";
+
+/// The key that requests are grouped by for rate limiting. Requests that have no key are
+/// never grouped with the requests of other clients, instead each connection has its own
+/// bucket. Please note that the HTTP server does not expose the address of the peer yet, so
+/// clients can't be limited by their address.
+#[derive(Clone)]
+pub enum RateLimitKey {
+	/// The session of the client. The session cookie is chosen by the client, so a client
+	/// that sends a new (or no) session id with each request is only limited per connection.
+	/// This key only limits clients that keep their session, use a custom key to limit
+	/// anonymous clients.
+	Session,
+	/// A key returned by the specified function, for example an API key header. The function
+	/// returns None if the request has no key.
+	Custom(Arc<dyn Fn(&mut RustletRequest) -> Result<Option<String>, Error> + Send + Sync>),
+}
+
+// the key of a bucket. Requests without a key have a bucket per connection.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum BucketKey {
+	Key(String),
+	Connection(u128),
+}
+
+/// The rate limit of a uri pattern. Each client may send `burst` requests at once and
+/// `rate` requests per second after that. There is no default configuration, as the key
+/// that clients are identified by must be chosen explicitly.
+#[derive(Clone)]
+pub struct RateLimitConfig {
+	/// The pattern of the uris that this limit applies to. Patterns are matched in the same
+	/// way as the patterns of filters, see [`crate::rustlet_filter`].
+	pub pattern: String,
+	/// The key that clients are identified by.
+	pub key: RateLimitKey,
+	/// The number of requests per second that are allowed once the burst is used up.
+	pub rate: f64,
+	/// The maximum number of requests that are allowed at once.
+	pub burst: u64,
+}
+
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+impl Bucket {
+	fn refill(&mut self, now: Instant, config: &RateLimitConfig) {
+		let elapsed = now.duration_since(self.last).as_secs_f64();
+		self.tokens = (self.tokens + elapsed * config.rate).min(config.burst as f64);
+		self.last = now;
+	}
+}
+
+// the rate limiter of a configuration. Each client has its own token bucket.
+pub(crate) struct RateLimiter {
+	config: RateLimitConfig,
+	buckets: RwLock<HashMap<BucketKey, Bucket>>,
+	// the number of rejected requests since they were last logged.
+	hits: AtomicU64,
+}
+
+// the rate limiters and concurrency limits of a container.
+pub(crate) struct Limits {
+	rate_limiters: RwLock<Vec<Arc<RateLimiter>>>,
+	// the number of running executions of each rustlet.
+	running: RwLock<HashMap<String, usize>>,
+	// the number of rejected requests of each rustlet since they were last logged.
+	concurrency_hits: RwLock<HashMap<String, u64>>,
+}
+
+impl Limits {
+	pub(crate) fn new() -> Self {
+		Limits {
+			rate_limiters: RwLock::new(vec![]),
+			running: RwLock::new(HashMap::new()),
+			concurrency_hits: RwLock::new(HashMap::new()),
+		}
+	}
+
+	// create the rate limiter of the specified configuration. It replaces the rate limiter
+	// of the same pattern, if any.
+	pub(crate) fn add_rate_limiter(
+		&self,
+		config: RateLimitConfig,
+	) -> Result<Arc<RateLimiter>, Error> {
+		let rate_limiter = Arc::new(RateLimiter {
+			config,
+			buckets: RwLock::new(HashMap::new()),
+			hits: AtomicU64::new(0),
+		});
+		let mut rate_limiters = nioruntime_util::lockw!(self.rate_limiters);
+		rate_limiters.retain(|r| r.config.pattern != rate_limiter.config.pattern);
+		rate_limiters.push(rate_limiter.clone());
+		Ok(rate_limiter)
+	}
+
+	// called by the housekeeper. Writes the limit hits since the last call to the stats log
+	// and removes the buckets that are full, as they are equivalent to new buckets.
+	pub(crate) fn housekeep(&self) -> Result<(), Error> {
+		let rate_limiters = nioruntime_util::lockr!(self.rate_limiters);
+		let now = Instant::now();
+		for rate_limiter in &*rate_limiters {
+			let hits = rate_limiter.hits.swap(0, Ordering::SeqCst);
+			if hits > 0 {
+				log_multi!(
+					INFO,
+					STATS_LOG,
+					"rate_limit '{}' rejected {} request(s)",
+					rate_limiter.config.pattern,
+					hits
+				);
+			}
+
+			let mut buckets = nioruntime_util::lockw!(rate_limiter.buckets);
+			buckets.retain(|_, bucket| {
+				bucket.refill(now, &rate_limiter.config);
+				bucket.tokens < rate_limiter.config.burst as f64
+			});
+		}
+
+		let concurrency_hits = {
+			let mut concurrency_hits = nioruntime_util::lockw!(self.concurrency_hits);
+			std::mem::replace(&mut *concurrency_hits, HashMap::new())
+		};
+		let mut concurrency_hits: Vec<(String, u64)> = concurrency_hits.into_iter().collect();
+		concurrency_hits.sort();
+		for (rustlet_name, hits) in concurrency_hits {
+			log_multi!(
+				INFO,
+				STATS_LOG,
+				"max_concurrency '{}' rejected {} request(s)",
+				rustlet_name,
+				hits
+			);
+		}
+
+		Ok(())
+	}
+}
+
+impl RateLimiter {
+	// take a token from the bucket of the specified key. Returns the number of seconds after
+	// which the client may retry if the bucket is empty.
+	fn take_token(&self, key: BucketKey, now: Instant) -> Result<Option<u64>, Error> {
+		let config = &self.config;
+		let mut buckets = nioruntime_util::lockw!(self.buckets);
+		let bucket = buckets.entry(key).or_insert(Bucket {
+			tokens: config.burst as f64,
+			last: now,
+		});
+		bucket.refill(now, config);
+		Ok(if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			None
+		} else if config.rate > 0.0 {
+			Some(((1.0 - bucket.tokens) / config.rate).ceil().max(1.0) as u64)
+		} else {
+			// the bucket is never refilled
+			Some(60)
+		})
+	}
+}
+
+// the key of the bucket of the request.
+fn bucket_key(key: &RateLimitKey, request: &mut RustletRequest) -> Result<BucketKey, Error> {
+	let key = match key {
+		RateLimitKey::Session => request.get_cookie("rustletsessionid")?,
+		RateLimitKey::Custom(f) => (f)(request)?,
+	};
+	Ok(match key {
+		Some(key) if key.len() > 0 => BucketKey::Key(key),
+		_ => BucketKey::Connection(request.get_connection_id()?),
+	})
+}
+
+// the filter that rejects the requests of clients that exceeded the rate limit.
+pub(crate) fn rate_limit_filter(
+	rate_limiter: &RateLimiter,
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+	phase: FilterPhase,
+) -> Result<FilterAction, Error> {
+	if phase != FilterPhase::Before {
+		return Ok(FilterAction::Continue);
+	}
+
+	let key = bucket_key(&rate_limiter.config.key, request)?;
+	match rate_limiter.take_token(key, Instant::now())? {
+		Some(retry_after) => {
+			rate_limiter.hits.fetch_add(1, Ordering::SeqCst);
+			reject(response, 429, retry_after)?;
+			Ok(FilterAction::Stop)
+		}
+		None => Ok(FilterAction::Continue),
+	}
+}
+
+// acquire a permit to execute the specified rustlet. Returns None if the maximum number of
+// concurrent executions is reached. The permit is released when it is dropped.
+pub(crate) fn acquire_permit(
+	limits: &Arc<Limits>,
+	rustlet_name: &str,
+	max_concurrency: usize,
+) -> Result<Option<ConcurrencyPermit>, Error> {
+	{
+		let mut running = nioruntime_util::lockw!(limits.running);
+		let count = running.entry(rustlet_name.to_string()).or_insert(0);
+		if *count < max_concurrency {
+			*count += 1;
+			return Ok(Some(ConcurrencyPermit {
+				limits: limits.clone(),
+				rustlet_name: rustlet_name.to_string(),
+			}));
+		}
+	}
+
+	let mut concurrency_hits = nioruntime_util::lockw!(limits.concurrency_hits);
+	*concurrency_hits
+		.entry(rustlet_name.to_string())
+		.or_insert(0) += 1;
+	Ok(None)
+}
+
+pub(crate) struct ConcurrencyPermit {
+	limits: Arc<Limits>,
+	rustlet_name: String,
+}
+
+impl Drop for ConcurrencyPermit {
+	fn drop(&mut self) {
+		let mut running = nioruntime_util::lockwp!(self.limits.running);
+		match running.get_mut(&self.rustlet_name) {
+			Some(count) => *count = count.saturating_sub(1),
+			None => {}
+		}
+	}
+}
+
+// write a response rejecting the request with the specified status and Retry-After header.
+pub(crate) fn reject(
+	response: &mut RustletResponse,
+	status: u16,
+	retry_after: u64,
+) -> Result<(), Error> {
+	response.set_status(status)?;
+	response.add_header("Retry-After", &format!("{}", retry_after))?;
+	response.set_content_type("text/plain")?;
+	let text = match status {
+		429 => "429 Too Many Requests",
+		_ => "503 Service Unavailable",
+	};
+	response.write(text.as_bytes())?;
+	Ok(())
+}
+
+// a request with the specified headers, received on the specified connection.
+#[cfg(test)]
+fn keyed_request(headers: Vec<(&str, &str)>, connection_id: u128) -> RustletRequest {
+	let mut request = crate::rustlet_impls::test_request("", b"", headers);
+	request.set_connection_id(connection_id);
+	request
+}
+
+#[test]
+fn test_rate_limit_keys() -> Result<(), Error> {
+	let session = RateLimitKey::Session;
+	let api_key = RateLimitKey::Custom(Arc::new(|request| request.get_header("X-Api-Key")));
+
+	assert_eq!(
+		bucket_key(
+			&session,
+			&mut keyed_request(vec![("Cookie", "rustletsessionid=5")], 1)
+		)?,
+		BucketKey::Key("5".to_string())
+	);
+	assert_eq!(
+		bucket_key(&api_key, &mut keyed_request(vec![("X-Api-Key", "abc")], 1))?,
+		BucketKey::Key("abc".to_string())
+	);
+	// requests without a key are never grouped with the requests of other clients
+	assert_eq!(
+		bucket_key(&session, &mut keyed_request(vec![], 1))?,
+		BucketKey::Connection(1)
+	);
+	assert_eq!(
+		bucket_key(&api_key, &mut keyed_request(vec![], 2))?,
+		BucketKey::Connection(2)
+	);
+	assert_eq!(
+		bucket_key(&api_key, &mut keyed_request(vec![("X-Api-Key", "")], 3))?,
+		BucketKey::Connection(3)
+	);
+	Ok(())
+}
+
+#[test]
+fn test_token_bucket() -> Result<(), Error> {
+	use std::time::Duration;
+
+	let rate_limiter = RateLimiter {
+		config: RateLimitConfig {
+			pattern: "/".to_string(),
+			key: RateLimitKey::Session,
+			rate: 2.0,
+			burst: 2,
+		},
+		buckets: RwLock::new(HashMap::new()),
+		hits: AtomicU64::new(0),
+	};
+	let key = BucketKey::Key("client".to_string());
+	let now = Instant::now();
+
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, Some(1));
+	// other clients have their own bucket
+	assert_eq!(
+		rate_limiter.take_token(BucketKey::Connection(1), now)?,
+		None
+	);
+	// the bucket is refilled at the rate, but never beyond the burst
+	let now = now + Duration::from_millis(500);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, Some(1));
+	let now = now + Duration::from_secs(60);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, Some(1));
+
+	// a bucket without a rate is never refilled
+	let rate_limiter = RateLimiter {
+		config: RateLimitConfig {
+			rate: 0.0,
+			burst: 1,
+			..rate_limiter.config.clone()
+		},
+		buckets: RwLock::new(HashMap::new()),
+		hits: AtomicU64::new(0),
+	};
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, None);
+	assert_eq!(rate_limiter.take_token(key.clone(), now)?, Some(60));
+	Ok(())
+}
+
+#[test]
+fn test_rate_limit_filter() -> Result<(), Error> {
+	use crate::rustlet_impls::http_request;
+	use crate::{RustletConfig, RustletContainer};
+	use std::time::Duration;
+
+	let port = 18_740;
+	let root_dir = format!(
+		"{}/rustlet_rate_limit_{}",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	let container = RustletContainer::builder()
+		.config(RustletConfig {
+			http_config: crate::HttpConfig {
+				host: "127.0.0.1".to_string(),
+				port,
+				root_dir: root_dir.clone(),
+				..crate::HttpConfig::default()
+			},
+			rate_limits: vec![RateLimitConfig {
+				pattern: "/limited".to_string(),
+				key: RateLimitKey::Session,
+				rate: 0.0,
+				burst: 2,
+			}],
+			..RustletConfig::default()
+		})
+		.rustlet("limited", |_request, response| response.write(b"allowed"))
+		.mapping("/limited", "limited")
+		.build()?;
+
+	let get = |headers: &str| {
+		http_request(
+			port,
+			&format!(
+				"GET /limited HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+				headers
+			),
+		)
+	};
+	let responses = (|| -> Result<Vec<String>, Error> {
+		let mut responses = vec![];
+		for _ in 0..3 {
+			responses.push(get("")?);
+		}
+		for _ in 0..3 {
+			responses.push(get("Cookie: rustletsessionid=42\r\n")?);
+		}
+		Ok(responses)
+	})();
+	container.stop(Duration::from_secs(5))?;
+	let _ = std::fs::remove_dir_all(&root_dir);
+
+	let responses = responses?;
+	// clients without a session are limited per connection rather than sharing a bucket
+	for response in &responses[..5] {
+		assert!(response.contains("allowed"), "{}", response);
+	}
+	assert!(responses[5].starts_with("HTTP/1.1 429"), "{}", responses[5]);
+	assert!(responses[5].contains("Retry-After: 60\r\n"));
+	Ok(())
+}
@@ -12,7 +12,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{Readable, Writeable};
+use crate::auth::{auth_filter, AuthConfig, PRINCIPAL_ATTR};
+use crate::cors::{cors_filter, CorsConfig};
+use crate::csrf::{csrf_filter, get_or_create_token, hidden_input, CsrfConfig};
+use crate::declaration::{declared_rustlets, method_name};
+use crate::plugin::Plugins;
+use crate::ratelimit::{acquire_permit, rate_limit_filter, reject, Limits, RateLimitConfig};
+use crate::{Readable, Reader, Writeable, Writer};
 use lazy_static::lazy_static;
 use nioruntime_err::{Error, ErrorKind};
 pub use nioruntime_http::{ConnData, HttpConfig, HttpServer};
@@ -20,24 +26,65 @@ use nioruntime_http::{HttpMethod, HttpVersion, State, WriteHandle};
 use nioruntime_log::*;
 use nioruntime_util::ser::BinReader;
 use nioruntime_util::ser::BinWriter;
-use std::collections::HashMap;
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::metadata;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::panic::AssertUnwindSafe;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::RwLockWriteGuard;
 use std::sync::{Arc, Mutex, RwLock};
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 info!();
 
 const HEADER_SIZE_LESS_SERVER_NAME: usize = 94;
 const MAIN_LOG: &str = "mainlog";
 const MAX_CHUNK_SIZE: usize = 1024 * 1024 * 10;
-const MAX_ESCAPE_SEQUENCE: usize = 100;
-const SEPARATOR_LINE: &str =
-	"------------------------------------------------------------------------------------------------------------------------------------";
+const RSP_KEYWORDS: &[&str] = &["if", "else", "end", "for", "page", "csrf"];
+// the HTTP server writes the request log from a thread that drains its queue every 100ms
+// and exits after its last drain once the server is stopped. The server neither exposes
+// the queue nor the thread, so stop waits for more than two drain intervals instead.
+const REQUEST_LOG_FLUSH_WAIT: Duration = Duration::from_millis(250);
+// the session file starts with the magic bytes and the version of its format, which is
+// incremented whenever the format changes.
+const SESSION_FILE_MAGIC: &[u8; 4] = b"RSLS";
+const SESSION_FILE_VERSION: u32 = 1;
+// the maximum number of containers that are started at the same time.
+const MAX_CONTAINERS: usize = 16;
+const HOUSEKEEPERS: [fn() -> Result<(), Error>; MAX_CONTAINERS] = [
+	housekeeper::<0>,
+	housekeeper::<1>,
+	housekeeper::<2>,
+	housekeeper::<3>,
+	housekeeper::<4>,
+	housekeeper::<5>,
+	housekeeper::<6>,
+	housekeeper::<7>,
+	housekeeper::<8>,
+	housekeeper::<9>,
+	housekeeper::<10>,
+	housekeeper::<11>,
+	housekeeper::<12>,
+	housekeeper::<13>,
+	housekeeper::<14>,
+	housekeeper::<15>,
+];
+
+// decrements the number of in-flight requests of a container when the request has been
+// processed.
+struct InFlightGuard {
+	state: Arc<ContainerState>,
+}
+
+impl Drop for InFlightGuard {
+	fn drop(&mut self) {
+		self.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+	}
+}
 
 #[derive(Clone)]
 pub struct RustletAsyncContext {
@@ -67,16 +114,144 @@ pub struct SessionData {
 
 impl SessionData {
 	fn new() -> Self {
-		let start_time = *START_TIME;
-		let now = Instant::now().duration_since(start_time).as_millis();
-
 		SessionData {
-			mod_time: now,
+			mod_time: session_time(),
 			data: HashMap::new(),
 		}
 	}
 }
 
+// the time of the last change of a session, in milliseconds since the unix epoch. Plugins
+// have their own copy of the statics of librustlet, so sessions that they change must not be
+// timed relative to a static of the library.
+fn session_time() -> u128 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map(|d| d.as_millis())
+		.unwrap_or(0)
+}
+
+/// A [`String`] that may be stored in the session. String values stored in the session
+/// in this format can be displayed in RSPs via expression tags such as `<@:session.user>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionString(pub String);
+
+impl Writeable for SessionString {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_bytes(self.0.as_bytes())?;
+		Ok(())
+	}
+}
+
+impl Readable for SessionString {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let bytes = reader.read_bytes_len_prefix()?;
+		Ok(SessionString(std::str::from_utf8(&bytes)?.to_string()))
+	}
+}
+
+// the attributes of a request. They are shared with the rustlets executed by an RSP's tags.
+type RequestAttrs = Arc<Mutex<HashMap<String, Box<dyn Any + Send>>>>;
+
+/// Data shared between an RSP and the rustlets that are executed by its tags.
+#[derive(Clone)]
+pub(crate) struct RspContext {
+	tag_attrs: HashMap<String, String>,
+	loop_iteration: Option<(usize, String)>,
+	condition: Arc<RwLock<bool>>,
+	loop_items: Arc<RwLock<Vec<String>>>,
+	request_attrs: Option<RequestAttrs>,
+	// the session of the page. All tags of an RSP use the same session.
+	session_id: Option<u128>,
+}
+
+impl RspContext {
+	pub(crate) fn new(
+		tag_attrs: HashMap<String, String>,
+		loop_iteration: Option<(usize, String)>,
+	) -> Self {
+		RspContext {
+			tag_attrs,
+			loop_iteration,
+			condition: Arc::new(RwLock::new(false)),
+			loop_items: Arc::new(RwLock::new(vec![])),
+			request_attrs: None,
+			session_id: None,
+		}
+	}
+}
+
+/// An error that occurred while processing a request. Error handlers registered via
+/// rustlet_error_handler! may retrieve it via the rustlet_error! macro.
+#[derive(Clone, Debug)]
+pub struct RustletError {
+	kind: ErrorKind,
+	uri: String,
+	status: u16,
+	details: Option<String>,
+}
+
+impl RustletError {
+	pub(crate) fn new(error: &Error, uri: &str, status: u16, debug: bool) -> Self {
+		RustletError {
+			kind: error.kind(),
+			uri: uri.to_string(),
+			status,
+			details: match debug {
+				true => Some(error_details(error)),
+				false => None,
+			},
+		}
+	}
+
+	/// The [`nioruntime_err::ErrorKind`] of the error.
+	pub fn kind(&self) -> ErrorKind {
+		self.kind.clone()
+	}
+
+	/// The uri of the request that generated the error.
+	pub fn uri(&self) -> String {
+		self.uri.clone()
+	}
+
+	/// The HTTP status of the response.
+	pub fn status(&self) -> u16 {
+		self.status
+	}
+
+	/// The details of the error: its kind, the chain of its causes and its backtrace, if one
+	/// was captured (see the RUST_BACKTRACE environment variable). Details are only available
+	/// if [`nioruntime_http::HttpConfig::debug`] is true.
+	pub fn details(&self) -> Option<String> {
+		self.details.clone()
+	}
+}
+
+// the kind of an error, followed by the chain of its causes and its backtrace, if any.
+fn error_details(error: &Error) -> String {
+	let mut details = format!("{}", error.kind());
+	let mut cause = error.cause();
+	loop {
+		match cause {
+			Some(c) => {
+				details.push_str(&format!("\nCaused by: {}", c));
+				cause = c.cause();
+			}
+			None => break,
+		}
+	}
+	match error.backtrace() {
+		Some(backtrace) => {
+			let backtrace = format!("{}", backtrace);
+			if !backtrace.trim().is_empty() {
+				details.push_str(&format!("\nBacktrace:\n{}", backtrace));
+			}
+		}
+		None => {}
+	}
+	details
+}
+
 #[derive(Clone)]
 pub struct RustletRequest {
 	content: Vec<u8>,
@@ -91,6 +266,10 @@ pub struct RustletRequest {
 	header_map: Option<HashMap<String, String>>,
 	session_map: Arc<RwLock<HashMap<u128, SessionData>>>,
 	session_id: u128,
+	rsp_context: RspContext,
+	error: Option<RustletError>,
+	attrs: RequestAttrs,
+	connection_id: u128,
 }
 
 impl RustletRequest {
@@ -118,6 +297,10 @@ impl RustletRequest {
 			header_map: None,
 			session_map,
 			session_id: 0,
+			rsp_context: RspContext::new(HashMap::new(), None),
+			error: None,
+			attrs: Arc::new(Mutex::new(HashMap::new())),
+			connection_id: 0,
 		}
 	}
 
@@ -127,6 +310,115 @@ impl RustletRequest {
 		Ok(())
 	}
 
+	pub(crate) fn set_connection_id(&mut self, connection_id: u128) {
+		self.connection_id = connection_id;
+	}
+
+	pub fn get_connection_id(&self) -> Result<u128, Error> {
+		Ok(self.connection_id)
+	}
+
+	pub fn is_tls(&self) -> Result<bool, Error> {
+		Ok(self.http_config.evh_config.tls_config.is_some())
+	}
+
+	pub fn get_local_port(&self) -> Result<u16, Error> {
+		Ok(self.http_config.port)
+	}
+
+	pub(crate) fn set_rsp_context(&mut self, rsp_context: RspContext) -> Result<(), Error> {
+		match &rsp_context.request_attrs {
+			Some(request_attrs) => self.attrs = request_attrs.clone(),
+			None => {}
+		}
+		self.rsp_context = rsp_context;
+
+		Ok(())
+	}
+
+	pub fn get_attr<T: Any + Clone>(&self, name: &str) -> Result<Option<T>, Error> {
+		let attrs = match self.attrs.lock() {
+			Ok(attrs) => attrs,
+			Err(e) => e.into_inner(),
+		};
+		Ok(match attrs.get(name) {
+			Some(value) => value.downcast_ref::<T>().map(|value| value.clone()),
+			None => None,
+		})
+	}
+
+	pub fn set_attr<T: Any + Send>(&mut self, name: &str, value: T) -> Result<(), Error> {
+		let mut attrs = match self.attrs.lock() {
+			Ok(attrs) => attrs,
+			Err(e) => e.into_inner(),
+		};
+		attrs.insert(name.to_string(), Box::new(value));
+		Ok(())
+	}
+
+	pub fn remove_attr(&mut self, name: &str) -> Result<(), Error> {
+		let mut attrs = match self.attrs.lock() {
+			Ok(attrs) => attrs,
+			Err(e) => e.into_inner(),
+		};
+		attrs.remove(name);
+		Ok(())
+	}
+
+	pub fn get_csrf_token(&mut self) -> Result<String, Error> {
+		get_or_create_token(self)
+	}
+
+	pub fn get_principal(&self) -> Result<Option<String>, Error> {
+		self.get_attr(PRINCIPAL_ATTR)
+	}
+
+	pub(crate) fn get_attrs(&self) -> RequestAttrs {
+		self.attrs.clone()
+	}
+
+	pub(crate) fn set_error(&mut self, error: Option<RustletError>) -> Result<(), Error> {
+		self.error = error;
+
+		Ok(())
+	}
+
+	pub fn get_error(&self) -> Result<Option<RustletError>, Error> {
+		Ok(self.error.clone())
+	}
+
+	pub fn get_tag_attr(&self, name: &str) -> Result<Option<String>, Error> {
+		Ok(self
+			.rsp_context
+			.tag_attrs
+			.get(name)
+			.map(|value| value.clone()))
+	}
+
+	pub fn set_rsp_condition(&mut self, value: bool) -> Result<(), Error> {
+		(*nioruntime_util::lockw!(self.rsp_context.condition)) = value;
+		Ok(())
+	}
+
+	pub fn set_rsp_loop_items(&mut self, items: Vec<String>) -> Result<(), Error> {
+		(*nioruntime_util::lockw!(self.rsp_context.loop_items)) = items;
+		Ok(())
+	}
+
+	pub fn get_rsp_loop_value(&self) -> Result<Option<String>, Error> {
+		Ok(match &self.rsp_context.loop_iteration {
+			Some((_, value)) => Some(value.clone()),
+			None => None,
+		})
+	}
+
+	pub fn get_rsp_loop_index(&self) -> Result<Option<usize>, Error> {
+		Ok(match &self.rsp_context.loop_iteration {
+			Some((index, _)) => Some(*index),
+			None => None,
+		})
+	}
+
 	pub fn get_session<T: Readable>(&mut self, name: &str) -> Result<Option<T>, Error> {
 		let mut create_session = false;
 		{
@@ -134,9 +426,7 @@ impl RustletRequest {
 			match session_map.get_mut(&self.session_id) {
 				Some(mut data) => {
 					let value = data.data.get(&name.to_string());
-					let start_time = *START_TIME;
-					let now = Instant::now().duration_since(start_time).as_millis();
-					data.mod_time = now;
+					data.mod_time = session_time();
 					match value {
 						Some(value) => {
 							return Ok(Some(Readable::read(&mut BinReader::new(
@@ -166,9 +456,7 @@ impl RustletRequest {
 				let mut writer = BinWriter::new(&mut sink);
 				value.write(&mut writer)?;
 				session_data.data.insert(name.to_string(), sink);
-				let start_time = *START_TIME;
-				let now = Instant::now().duration_since(start_time).as_millis();
-				session_data.mod_time = now;
+				session_data.mod_time = session_time();
 			}
 			None => {
 				let mut session_data = SessionData::new();
@@ -176,9 +464,7 @@ impl RustletRequest {
 				let mut writer = BinWriter::new(&mut sink);
 				value.write(&mut writer)?;
 				session_data.data.insert(name.to_string(), sink);
-				let start_time = *START_TIME;
-				let now = Instant::now().duration_since(start_time).as_millis();
-				session_data.mod_time = now;
+				session_data.mod_time = session_time();
 				session_map.insert(self.session_id, session_data);
 			}
 		};
@@ -192,9 +478,7 @@ impl RustletRequest {
 		match session_map.get_mut(&self.session_id) {
 			Some(session_data) => {
 				session_data.data.remove(&name.to_string());
-				let start_time = *START_TIME;
-				let now = Instant::now().duration_since(start_time).as_millis();
-				session_data.mod_time = now;
+				session_data.mod_time = session_time();
 			}
 			None => {}
 		}
@@ -360,13 +644,15 @@ pub struct RustletResponse {
 	wh: WriteHandle,
 	config: HttpConfig,
 	headers_written: Arc<Mutex<bool>>,
-	additional_headers: Vec<(String, String)>,
+	additional_headers: Arc<RwLock<Vec<(String, String)>>>,
 	redirect: Arc<Mutex<Option<String>>>,
+	status: Arc<RwLock<Option<u16>>>,
 	keep_alive: bool,
 	chained: bool,
 	is_async: Arc<RwLock<bool>>,
 	buffer: Arc<RwLock<Vec<u8>>>,
 	is_complete: bool,
+	async_in_flight: Arc<AtomicUsize>,
 }
 
 impl RustletResponse {
@@ -382,12 +668,14 @@ impl RustletResponse {
 			config,
 			headers_written: Arc::new(Mutex::new(false)),
 			keep_alive,
-			additional_headers: vec![],
+			additional_headers: Arc::new(RwLock::new(vec![])),
 			redirect: Arc::new(Mutex::new(None)),
+			status: Arc::new(RwLock::new(None)),
 			chained,
 			buffer: Arc::new(RwLock::new(vec![])),
 			is_complete: false,
 			is_async: is_async.clone(),
+			async_in_flight: Arc::new(AtomicUsize::new(0)),
 		}
 	}
 
@@ -398,7 +686,7 @@ impl RustletResponse {
 			)
 			.into()),
 			false => {
-				self.additional_headers.push((
+				nioruntime_util::lockw!(self.additional_headers).push((
 					"Set-Cookie".to_string(),
 					format!("{}={}; {}", name, value, other),
 				));
@@ -450,7 +738,7 @@ impl RustletResponse {
 			)
 			.into())
 		} else {
-			self.additional_headers
+			nioruntime_util::lockw!(self.additional_headers)
 				.push((name.to_string(), value.to_string()));
 			Ok(())
 		}
@@ -463,29 +751,59 @@ impl RustletResponse {
 			)
 			.into())
 		} else {
-			self.additional_headers
-				.push(("Content-Type".to_string(), ctype.to_string()));
+			// only one content-type may be specified, so replace any previous value.
+			let mut additional_headers = nioruntime_util::lockw!(self.additional_headers);
+			additional_headers.retain(|(name, _)| !name.eq_ignore_ascii_case("Content-Type"));
+			additional_headers.push(("Content-Type".to_string(), ctype.to_string()));
+			Ok(())
+		}
+	}
+
+	pub fn set_status(&mut self, status: u16) -> Result<(), Error> {
+		if self.get_headers_written() {
+			Err(
+				ErrorKind::OrderingError("headers already written. Cannot set status".to_string())
+					.into(),
+			)
+		} else if status < 100 || status > 999 {
+			Err(ErrorKind::InternalError(format!("invalid http status: {}", status)).into())
+		} else {
+			(*nioruntime_util::lockw!(self.status)) = Some(status);
 			Ok(())
 		}
 	}
 
+	fn get_status(&self) -> Option<u16> {
+		match self.status.read() {
+			Ok(s) => *s,
+			Err(e) => *e.into_inner(),
+		}
+	}
+
 	fn calculate_buffer_size(&self, buffer_len: usize) -> Result<usize, Error> {
 		if self.get_headers_written() {
-			Ok(buffer_len)
-		} else if self.get_redirect().is_some() {
+			return Ok(buffer_len);
+		}
+
+		let additional_headers = nioruntime_util::lockr!(self.additional_headers);
+		let mut additional_header_buffer_len = 0;
+		let additional_headers_len = additional_headers.len();
+		for i in 0..additional_headers_len {
+			additional_header_buffer_len +=
+				additional_headers[i].0.len() + additional_headers[i].1.len() + 4;
+		}
+
+		if self.get_redirect().is_some() {
 			let redir_len = self
 				.get_redirect()
 				.as_ref()
 				.unwrap_or(&"".to_string())
 				.len();
-			Ok(redir_len + HEADER_SIZE_LESS_SERVER_NAME + self.config.server_name.len())
+			Ok(redir_len
+				+ additional_header_buffer_len
+				+ HEADER_SIZE_LESS_SERVER_NAME
+				+ self.config.server_name.len())
 		} else {
-			let mut additional_header_buffer_len = 0;
-			let additional_headers_len = self.additional_headers.len();
-			for i in 0..additional_headers_len {
-				additional_header_buffer_len +=
-					self.additional_headers[i].0.len() + self.additional_headers[i].1.len() + 4;
-			}
 			Ok(buffer_len
 				+ additional_header_buffer_len
 				+ HEADER_SIZE_LESS_SERVER_NAME
@@ -497,7 +815,9 @@ impl RustletResponse {
 		let mut buffer = nioruntime_util::lockw!(self.buffer);
 		let mut to_write: Vec<u8> = vec![];
 
-		if !self.get_headers_written() && !self.chained {
+		// chained responses share their header state with the page that they are part of,
+		// so whichever response flushes first writes the headers.
+		if !self.get_headers_written() {
 			let buffer_size = self.calculate_buffer_size(buffer.len())?;
 			let term_len = if self.is_complete && self.keep_alive {
 				7
@@ -510,16 +830,34 @@ impl RustletResponse {
 			to_write.resize(buffer_size, 'q' as u8);
 
 			self.set_headers_written(true);
+			let additional_headers = nioruntime_util::lockr!(self.additional_headers).clone();
+			let redirect = self.get_redirect();
 			let len = HttpServer::build_headers(
 				&self.config,
 				true,
 				false,
 				self.keep_alive,
-				self.additional_headers.clone(),
-				self.get_redirect(),
+				additional_headers,
+				redirect.clone(),
 				&mut to_write,
 			)?;
 			to_write.resize(len, 'q' as u8);
+
+			match self.get_status() {
+				Some(status) => {
+					if redirect.is_none() {
+						// build_headers always writes a '200 OK' status line, so replace it.
+						let status_line =
+							format!("HTTP/1.1 {} {}\r\n", status, status_text(status));
+						let line_end = match to_write.iter().position(|c| *c == '\n' as u8) {
+							Some(pos) => pos + 1,
+							None => 0,
+						};
+						to_write.splice(0..line_end, status_line.bytes());
+					}
+				}
+				None => {}
+			}
 		}
 
 		let buffer_len = buffer.len();
@@ -561,8 +899,20 @@ impl RustletResponse {
 		Ok(())
 	}
 
+	// set the counter of the async rustlets of the container that are not complete yet.
+	pub(crate) fn set_async_in_flight(&mut self, async_in_flight: Arc<AtomicUsize>) {
+		self.async_in_flight = async_in_flight;
+	}
+
 	pub fn set_is_async(&mut self, value: bool) -> Result<(), Error> {
-		(*nioruntime_util::lockw!(self.is_async)) = value;
+		let mut is_async = nioruntime_util::lockw!(self.is_async);
+		if *is_async != value {
+			match value {
+				true => self.async_in_flight.fetch_add(1, Ordering::SeqCst),
+				false => self.async_in_flight.fetch_sub(1, Ordering::SeqCst),
+			};
+		}
+		*is_async = value;
 		Ok(())
 	}
 
@@ -592,12 +942,98 @@ impl RustletResponse {
 	}
 }
 
+// the reason phrase for the specified http status code.
+fn status_text(status: u16) -> &'static str {
+	match status {
+		100 => "Continue",
+		101 => "Switching Protocols",
+		200 => "OK",
+		201 => "Created",
+		202 => "Accepted",
+		204 => "No Content",
+		206 => "Partial Content",
+		301 => "Moved Permanently",
+		302 => "Found",
+		303 => "See Other",
+		304 => "Not Modified",
+		307 => "Temporary Redirect",
+		308 => "Permanent Redirect",
+		400 => "Bad Request",
+		401 => "Unauthorized",
+		403 => "Forbidden",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		406 => "Not Acceptable",
+		409 => "Conflict",
+		410 => "Gone",
+		411 => "Length Required",
+		413 => "Payload Too Large",
+		415 => "Unsupported Media Type",
+		422 => "Unprocessable Entity",
+		429 => "Too Many Requests",
+		500 => "Internal Server Error",
+		501 => "Not Implemented",
+		502 => "Bad Gateway",
+		503 => "Service Unavailable",
+		504 => "Gateway Timeout",
+		_ => match status / 100 {
+			1 => "Informational",
+			2 => "Success",
+			3 => "Redirection",
+			4 => "Client Error",
+			_ => "Server Error",
+		},
+	}
+}
+
 pub type Rustlet =
 	Pin<Box<dyn Fn(&mut RustletRequest, &mut RustletResponse) -> Result<(), Error> + Send + Sync>>;
 
+pub type RustletFilter = Pin<
+	Box<
+		dyn Fn(
+				&mut RustletRequest,
+				&mut RustletResponse,
+				FilterPhase,
+			) -> Result<FilterAction, Error>
+			+ Send
+			+ Sync,
+	>,
+>;
+
+/// The phase in which a filter is called. Filters are called before the rustlet is executed
+/// and again after it has executed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterPhase {
+	Before,
+	After,
+}
+
+/// The action returned by a filter. If a filter returns [`FilterAction::Stop`] in the
+/// [`FilterPhase::Before`] phase, the request is not processed further and the response
+/// written by the filter is returned to the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+	Continue,
+	Stop,
+}
+
+struct FilterHolder {
+	name: String,
+	pattern: String,
+	filter: Pin<Box<RustletFilter>>,
+}
+
+// the rustlets and filters are reference counted, so that requests can run them after
+// releasing the lock. Changing a rustlet or filter doesn't affect requests that are running
+// the old one.
 pub(crate) struct RustletContainerHolder {
-	rustlets: HashMap<String, Pin<Box<Rustlet>>>,
+	rustlets: HashMap<String, Arc<Rustlet>>,
 	mappings: HashMap<String, String>,
+	host_mappings: HashMap<String, HashMap<String, String>>,
+	filters: Vec<Arc<FilterHolder>>,
+	not_found: Option<String>,
+	error_handler: Option<String>,
 }
 
 impl RustletContainerHolder {
@@ -605,18 +1041,55 @@ impl RustletContainerHolder {
 		RustletContainerHolder {
 			rustlets: HashMap::new(),
 			mappings: HashMap::new(),
+			host_mappings: HashMap::new(),
+			filters: vec![],
+			not_found: None,
+			error_handler: None,
+		}
+	}
+}
+
+// the state of a rustlet container. It is shared with the callbacks of its HTTP server.
+pub(crate) struct ContainerState {
+	rustlets: RwLock<RustletContainerHolder>,
+	session_map: Arc<RwLock<HashMap<u128, SessionData>>>,
+	config: RwLock<Option<RustletConfig>>,
+	http: RwLock<Option<HttpServer>>,
+	panic_counts: RwLock<HashMap<String, u64>>,
+	limits: Arc<Limits>,
+	// the number of requests being processed and of async rustlets that are not complete yet.
+	in_flight: AtomicUsize,
+	async_in_flight: Arc<AtomicUsize>,
+	// set while the container is stopping. New requests are rejected.
+	stopping: AtomicBool,
+	plugins: RwLock<Option<Plugins>>,
+}
+
+impl ContainerState {
+	fn new() -> Self {
+		ContainerState {
+			rustlets: RwLock::new(RustletContainerHolder::new()),
+			session_map: Arc::new(RwLock::new(HashMap::new())),
+			config: RwLock::new(None),
+			http: RwLock::new(None),
+			panic_counts: RwLock::new(HashMap::new()),
+			limits: Arc::new(Limits::new()),
+			in_flight: AtomicUsize::new(0),
+			async_in_flight: Arc::new(AtomicUsize::new(0)),
+			stopping: AtomicBool::new(false),
+			plugins: RwLock::new(None),
 		}
 	}
 }
 
 lazy_static! {
-	pub(crate) static ref RUSTLETS: Arc<RwLock<RustletContainerHolder>> =
-		Arc::new(RwLock::new(RustletContainerHolder::new()));
-	pub(crate) static ref SESSION_MAP: Arc<RwLock<HashMap<u128, SessionData>>> =
+	// the started containers, keyed by the host and port of their HTTP server. The callbacks
+	// of the HTTP server are plain functions, so they look up their container here.
+	static ref CONTAINERS: Arc<RwLock<HashMap<String, Arc<ContainerState>>>> =
 		Arc::new(RwLock::new(HashMap::new()));
-	pub(crate) static ref RUSTLET_CONFIG: Arc<RwLock<Option<RustletConfig>>> =
-		Arc::new(RwLock::new(None));
-	static ref START_TIME: Instant = Instant::now();
+	// the containers that the housekeepers in HOUSEKEEPERS housekeep, by slot.
+	static ref HOUSEKEEPER_SLOTS: RwLock<Vec<Option<Arc<ContainerState>>>> =
+		RwLock::new(vec![None; MAX_CONTAINERS]);
 	static ref KEEP_ALIVE: Vec<u8> = ['\r' as u8, '\n' as u8].to_vec();
 	static ref KEEP_ALIVE_COMPLETE: Vec<u8> =
 		['\r' as u8, '\n' as u8, '0' as u8, '\r' as u8, '\n' as u8, '\r' as u8, '\n' as u8,]
@@ -630,6 +1103,32 @@ pub struct RustletConfig {
 	pub session_timeout: u64,
 	/// The [`nioruntime_http::HttpConfig`] configuration for this container.
 	pub http_config: HttpConfig,
+	/// The authentication configuration of this container. Requests for uris that match the
+	/// pattern of an [`crate::auth::AuthConfig`] must be authenticated. The default value is
+	/// an empty list.
+	pub auth: Vec<AuthConfig>,
+	/// The configuration of the CSRF filter. If set, non-GET requests for uris that match its
+	/// pattern must include the CSRF token of the session or a same-origin Origin header. See
+	/// [`crate::csrf`] for details. The default value is None.
+	pub csrf: Option<CsrfConfig>,
+	/// The CORS configuration of this container. Each [`crate::cors::CorsConfig`] applies to
+	/// the uris that match its pattern. If more than one matches, the first one is used. The
+	/// default value is an empty list.
+	pub cors: Vec<CorsConfig>,
+	/// The rate limits of this container. Each [`crate::ratelimit::RateLimitConfig`] applies
+	/// to the uris that match its pattern. The default value is an empty list.
+	pub rate_limits: Vec<RateLimitConfig>,
+	/// The maximum number of concurrent executions of rustlets, keyed by rustlet name.
+	/// Rustlets that are not listed are not limited. The default value is an empty map.
+	pub max_concurrency: HashMap<String, usize>,
+	/// The file that sessions are saved to when the container is stopped via
+	/// [`RustletContainer::stop`] and loaded from when it is started. A file that can't be
+	/// read is ignored with a warning. If None, sessions are not persisted. The default value
+	/// is None.
+	pub session_file: Option<String>,
+	/// Whether rustlets are loaded from the plugins in the `plugins` directory of the root
+	/// directory. See [`crate::plugin`] for details. The default value is false.
+	pub plugins: bool,
 }
 
 impl Default for RustletConfig {
@@ -637,13 +1136,77 @@ impl Default for RustletConfig {
 		RustletConfig {
 			session_timeout: 60 * 30, // 30 mins
 			http_config: HttpConfig::default(),
+			auth: vec![],
+			csrf: None,
+			cors: vec![],
+			rate_limits: vec![],
+			max_concurrency: HashMap::new(),
+			session_file: None,
+			plugins: false,
+		}
+	}
+}
+
+fn container_key(config: &HttpConfig) -> String {
+	format!("{}:{}", config.host, config.port)
+}
+
+// returns the state of the started containers.
+fn started_containers() -> Result<Vec<Arc<ContainerState>>, Error> {
+	let containers = nioruntime_util::lockr!(CONTAINERS);
+	Ok(containers.values().cloned().collect())
+}
+
+// the housekeeper callback of the HTTP server has no arguments, so each started container
+// is assigned a slot, whose housekeeper only housekeeps the container of the slot.
+fn housekeeper<const SLOT: usize>() -> Result<(), Error> {
+	let state = {
+		let slots = nioruntime_util::lockr!(HOUSEKEEPER_SLOTS);
+		slots[SLOT].clone()
+	};
+	match state {
+		Some(state) => match housekeep(&state) {
+			Ok(_) => {}
+			Err(e) => {
+				log_multi!(ERROR, MAIN_LOG, "error in housekeeper: {}", e.to_string());
+			}
+		},
+		None => {}
+	}
+	Ok(())
+}
+
+// assigns a housekeeper slot to the container and returns its housekeeper.
+fn acquire_housekeeper(state: &Arc<ContainerState>) -> Result<fn() -> Result<(), Error>, Error> {
+	let mut slots = nioruntime_util::lockw!(HOUSEKEEPER_SLOTS);
+	match slots.iter().position(|slot| slot.is_none()) {
+		Some(i) => {
+			slots[i] = Some(state.clone());
+			Ok(HOUSEKEEPERS[i])
+		}
+		None => Err(ErrorKind::SetupError(format!(
+			"no more than {} rustlet containers may be started",
+			MAX_CONTAINERS
+		))
+		.into()),
+	}
+}
+
+// releases the housekeeper slot of the container.
+fn release_housekeeper(state: &Arc<ContainerState>) -> Result<(), Error> {
+	let mut slots = nioruntime_util::lockw!(HOUSEKEEPER_SLOTS);
+	for slot in slots.iter_mut() {
+		match slot {
+			Some(slot_state) if Arc::ptr_eq(slot_state, state) => *slot = None,
+			_ => {}
 		}
 	}
+	Ok(())
 }
 
-fn housekeeper() -> Result<(), Error> {
+fn housekeep(state: &Arc<ContainerState>) -> Result<(), Error> {
 	let session_timeout = {
-		let config = nioruntime_util::lockr!(RUSTLET_CONFIG);
+		let config = nioruntime_util::lockr!(state.config);
 		match &(*config) {
 			Some(config) => config.session_timeout,
 			None => 0,
@@ -651,14 +1214,14 @@ fn housekeeper() -> Result<(), Error> {
 	};
 
 	if session_timeout > 0 {
-		let mut session_map = nioruntime_util::lockw!(SESSION_MAP);
+		let mut session_map = nioruntime_util::lockw!(state.session_map);
 
-		let start_time = *START_TIME;
-		let now = Instant::now().duration_since(start_time).as_millis();
+		let now = session_time();
 
 		let mut rem_list = vec![];
 		for (k, v) in &*session_map {
-			let diff = (now - v.mod_time) / 1000;
+			// the clock may have been set back since the session was changed
+			let diff = now.saturating_sub(v.mod_time) / 1000;
 			if diff > session_timeout.into() {
 				rem_list.push(k.clone());
 			}
@@ -669,18 +1232,196 @@ fn housekeeper() -> Result<(), Error> {
 		}
 	}
 
+	state.limits.housekeep()?;
+	check_plugins(state, false)?;
+
+	Ok(())
+}
+
+// check the plugins directory of the container for changes and apply them.
+fn check_plugins(state: &Arc<ContainerState>, force: bool) -> Result<(), Error> {
+	let (rustlet_names, mapping_paths): (HashSet<String>, HashSet<String>) = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+		(
+			rustlets.rustlets.keys().cloned().collect(),
+			rustlets.mappings.keys().cloned().collect(),
+		)
+	};
+	let (changes, errors) = {
+		let mut plugins = nioruntime_util::lockw!(state.plugins);
+		match &mut *plugins {
+			Some(plugins) => plugins.check(force, &rustlet_names, &mapping_paths)?,
+			None => return Ok(()),
+		}
+	};
+
+	for error in errors {
+		log_multi!(ERROR, MAIN_LOG, "{}", error);
+	}
+
+	let mut container = RustletContainer {
+		state: state.clone(),
+	};
+	let (added, removed) = (changes.add_rustlets.len(), changes.remove_rustlets.len());
+	for path in changes.remove_mappings {
+		// the mapping may have been removed already
+		let _ = container.remove_rustlet_mapping(&path);
+	}
+	for name in changes.remove_rustlets {
+		let _ = container.remove_rustlet(&name);
+	}
+	// requests that are running the old version of a rustlet complete with it
+	for (name, rustlet) in changes.add_rustlets {
+		container.add_rustlet(&name, rustlet)?;
+	}
+	for (path, name) in changes.add_mappings {
+		container.add_rustlet_mapping(&path, &name)?;
+	}
+
+	if added > 0 || removed > 0 {
+		log_multi!(
+			INFO,
+			MAIN_LOG,
+			"plugins: loaded {} rustlet(s), removed {} rustlet(s)",
+			added,
+			removed
+		);
+	}
+
+	Ok(())
+}
+
+// save the sessions to the specified file. Sessions may hold credentials, so they are
+// written to a temporary file that only the owner can read, which then replaces the file so
+// that it is never left partially written.
+fn save_sessions(state: &ContainerState, session_file: &str) -> Result<(), Error> {
+	let session_map = nioruntime_util::lockr!(state.session_map);
+	let tmp_file = format!("{}.tmp", session_file);
+	// the mode only applies to new files
+	let _ = std::fs::remove_file(&tmp_file);
+	let mut options = OpenOptions::new();
+	options.write(true).create_new(true);
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::OpenOptionsExt;
+		options.mode(0o600);
+	}
+	let mut file = options.open(&tmp_file)?;
+	write_sessions(&session_map, &mut file)?;
+	file.sync_all()?;
+	std::fs::rename(&tmp_file, session_file)?;
+	log_multi!(
+		INFO,
+		MAIN_LOG,
+		"saved {} session(s) to '{}'",
+		session_map.len(),
+		session_file
+	);
+	Ok(())
+}
+
+// load the sessions saved by save_sessions. A file that was written in another format or
+// that is corrupt, for example as it was truncated, is ignored.
+fn load_sessions(state: &ContainerState, session_file: &str) -> Result<(), Error> {
+	let mut file = match File::open(session_file) {
+		Ok(file) => file,
+		// nothing was saved yet
+		Err(_) => return Ok(()),
+	};
+	let sessions = match read_sessions(&mut file) {
+		Ok(sessions) => sessions,
+		Err(e) => {
+			log_multi!(
+				WARN,
+				MAIN_LOG,
+				"ignoring session file '{}': {}",
+				session_file,
+				e.to_string()
+			);
+			return Ok(());
+		}
+	};
+	let count = sessions.len();
+	let mut session_map = nioruntime_util::lockw!(state.session_map);
+	session_map.extend(sessions);
+	log_multi!(
+		INFO,
+		MAIN_LOG,
+		"loaded {} session(s) from '{}'",
+		count,
+		session_file
+	);
+	Ok(())
+}
+
+// write the sessions in the format of the session file: the magic bytes and the version of
+// the format, followed by the sessions.
+fn write_sessions(
+	session_map: &HashMap<u128, SessionData>,
+	sink: &mut dyn Write,
+) -> Result<(), Error> {
+	let mut writer = BinWriter::new(sink);
+	writer.write_fixed_bytes(SESSION_FILE_MAGIC)?;
+	writer.write_u32(SESSION_FILE_VERSION)?;
+	writer.write_u64(session_map.len().try_into().unwrap_or(0))?;
+	for (id, session) in session_map {
+		writer.write_fixed_bytes(id.to_be_bytes())?;
+		writer.write_u64(session.data.len().try_into().unwrap_or(0))?;
+		for (name, value) in &session.data {
+			writer.write_bytes(name.as_bytes())?;
+			writer.write_bytes(value)?;
+		}
+	}
 	Ok(())
 }
 
+// read the sessions written by write_sessions. Their timeouts start over.
+fn read_sessions<R: Read>(source: &mut R) -> Result<HashMap<u128, SessionData>, Error> {
+	let mut reader = BinReader::new(source);
+	if reader.read_fixed_bytes(SESSION_FILE_MAGIC.len())? != SESSION_FILE_MAGIC {
+		return Err(ErrorKind::CorruptedData("not a session file".to_string()).into());
+	}
+	let version = reader.read_u32()?;
+	if version != SESSION_FILE_VERSION {
+		return Err(ErrorKind::CorruptedData(format!(
+			"the version of the file is {}, expected {}",
+			version, SESSION_FILE_VERSION
+		))
+		.into());
+	}
+	let mut sessions = HashMap::new();
+	let count = reader.read_u64()?;
+	for _ in 0..count {
+		let id: [u8; 16] = reader.read_fixed_bytes(16)?[..]
+			.try_into()
+			.map_err(|_| ErrorKind::CorruptedData("invalid session id".to_string()))?;
+		let mut session = SessionData::new();
+		let entries = reader.read_u64()?;
+		for _ in 0..entries {
+			let name = String::from_utf8(reader.read_bytes_len_prefix()?).map_err(|e| {
+				ErrorKind::CorruptedData(format!("invalid session entry name: {}", e))
+			})?;
+			session.data.insert(name, reader.read_bytes_len_prefix()?);
+		}
+		sessions.insert(u128::from_be_bytes(id), session);
+	}
+	Ok(sessions)
+}
+
 fn on_panic() -> Result<(), Error> {
-	let container = nioruntime_util::lockw!(crate::macros::RUSTLET_CONTAINER);
-	match &container.http {
-		Some(http) => {
-			if http.http_context.is_some() {
-				HttpServer::do_house_keeping(http.http_context.as_ref().unwrap(), &http.config)?;
+	for state in started_containers()? {
+		let http = nioruntime_util::lockr!(state.http);
+		match &*http {
+			Some(http) => {
+				if http.http_context.is_some() {
+					HttpServer::do_house_keeping(
+						http.http_context.as_ref().unwrap(),
+						&http.config,
+					)?;
+				}
 			}
+			None => {}
 		}
-		None => {}
 	}
 	Ok(())
 }
@@ -700,21 +1441,55 @@ fn api_callback(
 	headers: Vec<(Vec<u8>, Vec<u8>)>,      // headers
 	keep_alive: bool,                      // keep-alive
 ) -> Result<(), Error> {
+	// clear any state left over from a previous request on this thread
+	crate::macros::LOCALRUSTLET.with(|f| {
+		*f.borrow_mut() = None;
+	});
+
+	let state = {
+		let containers = nioruntime_util::lockr!(CONTAINERS);
+		containers.get(&container_key(&config)).cloned()
+	};
+	let state = match state {
+		Some(state) => state,
+		None => {
+			log_multi!(
+				ERROR,
+				MAIN_LOG,
+				"no rustlet container is started on {}",
+				container_key(&config)
+			);
+			wh.close()?;
+			return Ok(());
+		}
+	};
+
+	if state.stopping.load(Ordering::SeqCst) {
+		let mut response = RustletResponse::new(conn_data_is_async, wh, config, false, false);
+		reject(&mut response, 503, 1)?;
+		response.complete()?;
+		return Ok(());
+	}
+	state.in_flight.fetch_add(1, Ordering::SeqCst);
+	let _in_flight = InFlightGuard {
+		state: state.clone(),
+	};
+
 	let res = do_api_callback(
 		conn_data_is_async.clone(),
 		conn_data,
 		has_content,
 		start_content,
 		end_content,
-		method,
+		method.clone(),
 		config.clone(),
 		wh.clone(),
-		version,
+		version.clone(),
 		uri,
 		query,
-		headers,
+		headers.clone(),
 		keep_alive,
-		SESSION_MAP.clone(),
+		&state,
 	);
 
 	match res {
@@ -729,59 +1504,193 @@ fn api_callback(
 				e.to_string()
 			);
 
-			let (headers_written, _redir) =
-				crate::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
-					Some((_request, response)) => {
-						(response.get_headers_written(), response.get_redirect())
-					}
-					None => (false, None),
-				});
-			if headers_written {
-				if !keep_alive {
-					let mut response =
-						RustletResponse::new(conn_data_is_async, wh.clone(), config, false, true);
-					response.write(
-						format!(
-							"{}{}{}",
-							"\n</br>",
-							SEPARATOR_LINE,
-							"\n</br>Internal Server error. See logs for details.</body></html>"
-						)
-						.as_bytes(),
-					)?;
-					wh.close()?;
-				} else {
-					let msg_str = format!(
-						"{}{}{}",
-						"\n</br>",
-						SEPARATOR_LINE,
-						"\n</br>Internal Server error. See logs for details.</body></html>"
-					);
-					let msg = msg_str.as_bytes();
-					let msg_len_bytes = format!("{:X}\r\n", msg.len());
-					wh.write(&msg_len_bytes.as_bytes()[0..msg_len_bytes.len()])?;
-					wh.write(&msg[0..msg.len()])?;
-					wh.write(&("\r\n0\r\n\r\n".as_bytes())[0..7])?;
-					wh.close()?;
-				}
-			} else {
-				let mut response =
-					RustletResponse::new(conn_data_is_async, wh.clone(), config, false, false);
-				response.write("Internal Server error. See logs for details.".as_bytes())?;
-				wh.close()?;
-			}
-		}
-	}
-
-	Ok(())
-}
-
-fn execute_rustlet(
-	conn_data_is_async: Arc<RwLock<bool>>,
-	rustlet_name: &str,
-	conn_data: &mut RwLockWriteGuard<ConnData>, // connection_data
-	has_content: bool,                          // whether this request has content
-	start_content: usize,                       // start content
+			let headers_written = crate::macros::LOCALRUSTLET.with(|f| match &(*f.borrow()) {
+				Some((_request, response)) => response.get_headers_written(),
+				None => false,
+			});
+			let error = RustletError::new(&e, uri, 500, config.debug);
+			let res = send_error(
+				conn_data_is_async,
+				conn_data,
+				has_content,
+				start_content,
+				end_content,
+				method,
+				config,
+				wh.clone(),
+				version,
+				uri,
+				query,
+				headers,
+				keep_alive,
+				&state,
+				headers_written,
+				error,
+			);
+
+			match res {
+				Ok(_) => {}
+				Err(e) => {
+					log_multi!(
+						ERROR,
+						MAIN_LOG,
+						"error handler for [{}?{}] generated error: '{}'",
+						uri,
+						query,
+						e.to_string()
+					);
+					wh.close()?;
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+// send an error response. If an error handler has been registered via
+// rustlet_error_handler!, it generates the content. Otherwise a default HTML or JSON
+// response is returned, based on the Accept header of the request.
+fn send_error(
+	conn_data_is_async: Arc<RwLock<bool>>,
+	conn_data: &mut RwLockWriteGuard<ConnData>, // connection_data
+	has_content: bool,
+	start_content: usize,
+	end_content: usize,
+	method: HttpMethod,               // GET or POST
+	config: HttpConfig,               // HttpServer's configuration
+	wh: WriteHandle,                  // WriteHandle to write back data
+	version: HttpVersion,             // HttpVersion
+	uri: &str,                        // uri
+	query: &str,                      // query
+	headers: Vec<(Vec<u8>, Vec<u8>)>, // headers
+	keep_alive: bool,                 // keep-alive
+	state: &Arc<ContainerState>,
+	headers_written: bool, // whether the headers were already written
+	error: RustletError,   // the error to report
+) -> Result<(), Error> {
+	let error_handler = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+		match &rustlets.error_handler {
+			Some(error_handler) => match rustlets.rustlets.get(error_handler).is_some() {
+				true => Some(error_handler.clone()),
+				false => {
+					log_multi!(
+						ERROR,
+						MAIN_LOG,
+						"error handler '{}' does not exist",
+						error_handler
+					);
+					None
+				}
+			},
+			None => None,
+		}
+	};
+
+	let mut response = RustletResponse::new(
+		conn_data_is_async.clone(),
+		wh.clone(),
+		config.clone(),
+		keep_alive,
+		false,
+	);
+	// if the headers were already written, the error is appended to the response
+	match headers_written {
+		true => response.set_headers_written(true),
+		false => response.set_status(error.status())?,
+	}
+
+	match error_handler {
+		Some(error_handler) => execute_rustlet(
+			conn_data_is_async,
+			&error_handler,
+			conn_data,
+			has_content,
+			start_content,
+			end_content,
+			method,
+			config,
+			wh,
+			version,
+			uri,
+			query,
+			headers,
+			keep_alive,
+			Some(response),
+			state,
+			RspContext::new(HashMap::new(), None),
+			Some(error),
+		),
+		None => {
+			let accept_json = headers.iter().any(|(name, value)| {
+				std::str::from_utf8(name)
+					.map(|name| name.eq_ignore_ascii_case("Accept"))
+					.unwrap_or(false)
+					&& std::str::from_utf8(value)
+						.map(|value| value.contains("application/json"))
+						.unwrap_or(false)
+			});
+			let status = error.status();
+			let content = match accept_json {
+				true => {
+					if !headers_written {
+						response.set_content_type("application/json")?;
+					}
+					format!(
+						"{{\"status\":{},\"error\":\"{}\"{}}}",
+						status,
+						status_text(status),
+						match error.details() {
+							Some(details) => format!(",\"details\":\"{}\"", json_escape(&details)),
+							None => "".to_string(),
+						}
+					)
+				}
+				false => {
+					if !headers_written {
+						response.set_content_type("text/html")?;
+					}
+					format!(
+						"<html><body><h1>{} {}</h1>See logs for details.{}</body></html>",
+						status,
+						status_text(status),
+						match error.details() {
+							Some(details) => format!("<pre>{}</pre>", html_escape(&details)),
+							None => "".to_string(),
+						}
+					)
+				}
+			};
+			response.write(content.as_bytes())?;
+			response.complete()
+		}
+	}
+}
+
+// escape the specified string for inclusion in a JSON string value.
+fn json_escape(value: &str) -> String {
+	let mut ret = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'"' => ret.push_str("\\\""),
+			'\\' => ret.push_str("\\\\"),
+			'\n' => ret.push_str("\\n"),
+			'\r' => ret.push_str("\\r"),
+			'\t' => ret.push_str("\\t"),
+			c if (c as u32) < 0x20 => ret.push_str(&format!("\\u{:04x}", c as u32)),
+			_ => ret.push(c),
+		}
+	}
+	ret
+}
+
+fn execute_rustlet(
+	conn_data_is_async: Arc<RwLock<bool>>,
+	rustlet_name: &str,
+	conn_data: &mut RwLockWriteGuard<ConnData>, // connection_data
+	has_content: bool,                          // whether this request has content
+	start_content: usize,                       // start content
 	end_content: usize,                         // end content
 	method: HttpMethod,                         // GET or POST
 	config: HttpConfig,                         // HttpServer's configuration
@@ -791,16 +1700,32 @@ fn execute_rustlet(
 	query: &str,                                // query
 	headers: Vec<(Vec<u8>, Vec<u8>)>,           // headers
 	keep_alive: bool,                           // keep-alive
-	chained: bool,                              // is this a chained rustlet call?
-	session_map: Arc<RwLock<HashMap<u128, SessionData>>>,
+	rsp_response: Option<RustletResponse>,      // the response to use (RSPs and not found)
+	state: &Arc<ContainerState>,
+	rsp_context: RspContext,     // context of the RSP tag (chained calls only)
+	error: Option<RustletError>, // the error being handled (error handlers only)
 ) -> Result<(), Error> {
-	let rustlets = nioruntime_util::lockr!(RUSTLETS);
-	let rustlet = rustlets.rustlets.get(rustlet_name);
+	let connection_id = wh.get_connection_id();
+	// filters only apply to the rustlet that a request is mapped to
+	let rsp_response_is_none = rsp_response.is_none();
+	// the lock isn't held while the rustlet runs, as it may change the rustlets itself
+	let (rustlet, filters) = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+		let filters = match rsp_response_is_none {
+			true => rustlets.filters.clone(),
+			false => vec![],
+		};
+		(rustlets.rustlets.get(rustlet_name).cloned(), filters)
+	};
 
 	match rustlet {
 		Some(rustlet) => {
-			let mut response =
-				RustletResponse::new(conn_data_is_async, wh, config.clone(), keep_alive, chained);
+			let mut response = match rsp_response {
+				Some(rsp_response) => rsp_response,
+				None => {
+					RustletResponse::new(conn_data_is_async, wh, config.clone(), keep_alive, false)
+				}
+			};
 			let content = match has_content {
 				true => (*conn_data).get_buffer()[start_content..end_content].to_vec(),
 				false => vec![],
@@ -814,10 +1739,14 @@ fn execute_rustlet(
 				config,
 				headers,
 				keep_alive,
-				session_map,
+				state.session_map.clone(),
 			);
 			let id: u128 = rand::random();
-			let rsessionid = request.get_cookie("rustletsessionid");
+			let rsessionid = match rsp_context.session_id {
+				// the session of the RSP, whose cookie was already set if it is new
+				Some(session_id) => Ok(Some(format!("{}", session_id))),
+				None => request.get_cookie("rustletsessionid"),
+			};
 
 			let rsessionid = match rsessionid {
 				Ok(rsessionid) => match rsessionid {
@@ -839,33 +1768,153 @@ fn execute_rustlet(
 			};
 
 			if rsessionid == id {
-				// we have to set this as it's a new id
+				// we have to set this as it's a new id. RSPs create the session before
+				// anything is written, so the headers can only have been written if the
+				// response was flushed by another rustlet that shares it.
+				if response.get_headers_written() {
+					return Err(ErrorKind::InternalError(format!(
+						"rustlet '{}' can't create a session after the headers were written",
+						rustlet_name
+					))
+					.into());
+				}
 				response.set_cookie("rustletsessionid", &format!("{}", id), "path=/")?;
 			}
 
 			request.set_session_id(rsessionid)?;
-			(rustlet)(&mut request, &mut response).map_err(|e| {
-				match response.flush() {
-					Ok(_) => {}
-					Err(e) => {
-						log_multi!(ERROR, MAIN_LOG, "error flushing: {}", e.to_string());
+			request.set_connection_id(connection_id);
+			response.set_async_in_flight(state.async_in_flight.clone());
+			request.set_rsp_context(rsp_context)?;
+			request.set_error(error)?;
+
+			// the concurrency limit, like the filters, only applies to mapped rustlets
+			let max_concurrency = match rsp_response_is_none {
+				true => {
+					let config = nioruntime_util::lockr!(state.config);
+					match &(*config) {
+						Some(config) => config.max_concurrency.get(rustlet_name).cloned(),
+						None => None,
+					}
+				}
+				false => None,
+			};
+			let _permit = match max_concurrency {
+				Some(max_concurrency) => {
+					match acquire_permit(&state.limits, rustlet_name, max_concurrency)? {
+						Some(permit) => Some(permit),
+						None => {
+							log_multi!(
+								WARN,
+								MAIN_LOG,
+								"rustlet '{}' reached its maximum concurrency of {}",
+								rustlet_name,
+								max_concurrency
+							);
+							reject(&mut response, 503, 1)?;
+							response.complete()?;
+							return Ok(());
+						}
 					}
 				}
+				None => None,
+			};
+			let res = std::panic::catch_unwind(AssertUnwindSafe(|| {
+				let (proceed, filters_run) =
+					run_filters_before(&filters, uri, &mut request, &mut response)?;
+				if proceed {
+					(rustlet)(&mut request, &mut response).map_err(|e| {
+						match response.flush() {
+							Ok(_) => {}
+							Err(e) => {
+								log_multi!(ERROR, MAIN_LOG, "error flushing: {}", e.to_string());
+							}
+						}
+
+						return e;
+					})?;
+				}
+				run_filters_after(&filters, &filters_run, &mut request, &mut response)
+			}));
+			let res = match res {
+				Ok(res) => res,
+				Err(payload) => {
+					let msg = match payload.downcast_ref::<&str>() {
+						Some(msg) => msg.to_string(),
+						None => match payload.downcast_ref::<String>() {
+							Some(msg) => msg.clone(),
+							None => "unknown".to_string(),
+						},
+					};
+					let panic_count = {
+						let mut panic_counts = nioruntime_util::lockw!(state.panic_counts);
+						let panic_count = panic_counts.entry(rustlet_name.to_string()).or_insert(0);
+						*panic_count += 1;
+						*panic_count
+					};
+					log_multi!(
+						ERROR,
+						MAIN_LOG,
+						"rustlet '{}' panicked while processing '{}': '{}' (panic count = {})",
+						rustlet_name,
+						uri,
+						msg,
+						panic_count
+					);
 
-				return e;
-			})?;
+					// make sure the error response knows whether the headers were written.
+					crate::macros::LOCALRUSTLET.with(|f| {
+						*f.borrow_mut() = Some((request.clone(), response.clone()));
+					});
+					return Err(ErrorKind::InternalError(format!(
+						"rustlet '{}' panicked: {}",
+						rustlet_name, msg
+					))
+					.into());
+				}
+			};
+			res?;
 			response.complete()?;
 		}
-		None => {
-			let mut response =
-				RustletResponse::new(conn_data_is_async, wh.clone(), config, keep_alive, chained);
-			response.write(format!("Rustlet '{}' does not exist.", rustlet_name).as_bytes())?;
-			if keep_alive {
-				wh.write(&("0\r\n\r\n".as_bytes())[0..5])?;
-			} else {
-				wh.close()?;
+		None => match rsp_response {
+			Some(mut response) => {
+				// logged at debug level as this happens for each request
+				log_multi!(
+					DEBUG,
+					MAIN_LOG,
+					"rustlet '{}' referenced by '{}' does not exist",
+					rustlet_name,
+					uri
+				);
+				response.write(format!("Rustlet '{}' does not exist.", rustlet_name).as_bytes())?;
+				response.complete()?;
 			}
-		}
+			None => {
+				// logged at debug level as this happens for each request
+				log_multi!(
+					DEBUG,
+					MAIN_LOG,
+					"rustlet '{}' mapped to '{}' does not exist",
+					rustlet_name,
+					uri
+				);
+				send_not_found(
+					conn_data_is_async,
+					conn_data,
+					has_content,
+					start_content,
+					end_content,
+					method,
+					config,
+					wh,
+					version,
+					uri,
+					query,
+					headers,
+					keep_alive,
+					state,
+				)?;
+			}
+		},
 	}
 	Ok(())
 }
@@ -884,16 +1933,29 @@ fn do_api_callback(
 	query: &str,                      // query
 	headers: Vec<(Vec<u8>, Vec<u8>)>, // headers
 	keep_alive: bool,                 // keep-alive
-	session_map: Arc<RwLock<HashMap<u128, SessionData>>>,
+	state: &Arc<ContainerState>,
 ) -> Result<(), Error> {
-	let rustlets = nioruntime_util::lockr!(RUSTLETS);
-
-	let rustlet = rustlets.mappings.get(uri);
+	let rustlet = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+
+		// mappings of the requested host take precedence over the global mappings
+		let host_rustlet = match request_host(&headers) {
+			Some(host) => match rustlets.host_mappings.get(&host) {
+				Some(host_mappings) => host_mappings.get(uri),
+				None => None,
+			},
+			None => None,
+		};
+		match host_rustlet {
+			Some(rustlet) => Some(rustlet.clone()),
+			None => rustlets.mappings.get(uri).cloned(),
+		}
+	};
 	match rustlet {
 		Some(rustlet_name) => {
 			execute_rustlet(
 				conn_data_is_async,
-				rustlet_name,
+				&rustlet_name,
 				conn_data,
 				has_content,
 				start_content,
@@ -906,62 +1968,190 @@ fn do_api_callback(
 				query,
 				headers,
 				keep_alive,
-				false,
-				session_map,
+				None,
+				state,
+				RspContext::new(HashMap::new(), None),
+				None,
 			)?;
 		}
 		None => {
 			// see if it's an RSP.
-			if uri.to_lowercase().ends_with(".rsp") {
-				let res = process_rsp(
-					conn_data_is_async.clone(),
+			if uri.to_lowercase().ends_with(".rsp") && rsp_exists(&config, uri) {
+				process_rsp(
+					conn_data_is_async,
 					conn_data,
 					has_content,
 					start_content,
 					end_content,
 					method,
-					config.clone(),
-					wh.clone(),
+					config,
+					wh,
 					version,
 					uri,
 					query,
 					headers,
 					keep_alive,
-					session_map,
-				);
+					state,
+					uri,
+					None,
+				)?;
+			} else {
+				send_not_found(
+					conn_data_is_async,
+					conn_data,
+					has_content,
+					start_content,
+					end_content,
+					method,
+					config,
+					wh,
+					version,
+					uri,
+					query,
+					headers,
+					keep_alive,
+					state,
+				)?;
+			}
+		}
+	}
 
-				match res {
-					Ok(_) => {}
-					Err(e) => {
-						log_multi!(
-							ERROR,
-							MAIN_LOG,
-							"rsp '{}' generated error: {}",
-							uri,
-							e.to_string()
-						);
-						let mut response = RustletResponse::new(
-							conn_data_is_async,
-							wh.clone(),
-							config,
-							false,
-							false,
-						);
-						response
-							.write("Internal Server error. See logs for details.".as_bytes())?;
-						response.complete()?;
-					}
-				}
+	Ok(())
+}
+
+// returns the lowercase host of the Host header, without the port.
+fn request_host(headers: &Vec<(Vec<u8>, Vec<u8>)>) -> Option<String> {
+	let value = headers
+		.iter()
+		.find(|(name, _)| name.eq_ignore_ascii_case(b"host"))
+		.map(|(_, value)| value)?;
+	let host = std::str::from_utf8(value).ok()?.trim().to_lowercase();
+	let host = match host.starts_with('[') {
+		// IPv6 literal
+		true => match host.find(']') {
+			Some(end) => host[..(end + 1)].to_string(),
+			None => host,
+		},
+		false => match host.find(':') {
+			Some(pos) => host[..pos].to_string(),
+			None => host,
+		},
+	};
+	Some(host)
+}
+
+// returns true if the RSP at the specified uri exists in the webroot.
+fn rsp_exists(config: &HttpConfig, uri: &str) -> bool {
+	match HttpServer::get_path(config, uri) {
+		Ok(rsp_path) => match metadata(rsp_path) {
+			Ok(md) => md.is_file(),
+			Err(_) => false,
+		},
+		Err(_) => false,
+	}
+}
+
+// send a 404 response for the specified uri. If a not found rustlet or RSP has been
+// registered via rustlet_not_found!, it generates the content. Otherwise a default page is
+// returned.
+fn send_not_found(
+	conn_data_is_async: Arc<RwLock<bool>>,
+	conn_data: &mut RwLockWriteGuard<ConnData>, // connection_data
+	has_content: bool,
+	start_content: usize,
+	end_content: usize,
+	method: HttpMethod,               // GET or POST
+	config: HttpConfig,               // HttpServer's configuration
+	wh: WriteHandle,                  // WriteHandle to write back data
+	version: HttpVersion,             // HttpVersion
+	uri: &str,                        // uri
+	query: &str,                      // query
+	headers: Vec<(Vec<u8>, Vec<u8>)>, // headers
+	keep_alive: bool,                 // keep-alive
+	state: &Arc<ContainerState>,
+) -> Result<(), Error> {
+	let (not_found, is_rustlet) = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+		match &rustlets.not_found {
+			Some(not_found) => (
+				Some(not_found.clone()),
+				rustlets.rustlets.get(not_found).is_some(),
+			),
+			None => (None, false),
+		}
+	};
+
+	let mut response = RustletResponse::new(
+		conn_data_is_async.clone(),
+		wh.clone(),
+		config.clone(),
+		keep_alive,
+		false,
+	);
+	response.set_status(404)?;
+
+	match not_found {
+		Some(not_found) => {
+			if is_rustlet {
+				return execute_rustlet(
+					conn_data_is_async,
+					&not_found,
+					conn_data,
+					has_content,
+					start_content,
+					end_content,
+					method,
+					config,
+					wh,
+					version,
+					uri,
+					query,
+					headers,
+					keep_alive,
+					Some(response),
+					state,
+					RspContext::new(HashMap::new(), None),
+					None,
+				);
+			} else if not_found.to_lowercase().ends_with(".rsp") && rsp_exists(&config, &not_found)
+			{
+				return process_rsp(
+					conn_data_is_async,
+					conn_data,
+					has_content,
+					start_content,
+					end_content,
+					method,
+					config,
+					wh,
+					version,
+					uri,
+					query,
+					headers,
+					keep_alive,
+					state,
+					&not_found,
+					Some(404),
+				);
 			} else {
-				log_multi!(ERROR, MAIN_LOG, "error, no mapping for '{}'", uri);
-				let mut response =
-					RustletResponse::new(conn_data_is_async, wh.clone(), config, false, false);
-				response.write("Internal Server error. See logs for details.".as_bytes())?;
-				wh.close()?;
+				log_multi!(
+					ERROR,
+					MAIN_LOG,
+					"not found handler '{}' is neither a rustlet nor an RSP",
+					not_found
+				);
 			}
 		}
+		None => {}
 	}
 
+	response.set_content_type("text/html")?;
+	response.write(
+		"<html><body><h1>404 Not Found</h1>The requested resource was not found.</body></html>"
+			.as_bytes(),
+	)?;
+	response.complete()?;
+
 	Ok(())
 }
 
@@ -979,155 +2169,839 @@ fn process_rsp(
 	query: &str,                      // query
 	headers: Vec<(Vec<u8>, Vec<u8>)>, // headers
 	keep_alive: bool,                 // keep-alive
-	session_map: Arc<RwLock<HashMap<u128, SessionData>>>,
+	state: &Arc<ContainerState>,
+	rsp_uri: &str,       // uri of the RSP to process
+	status: Option<u16>, // status of the response, if not 200
 ) -> Result<(), Error> {
-	let rsp_path = HttpServer::get_path(&config, uri)?;
-	let mut flen = metadata(rsp_path.clone())?.len();
-	let mut file = File::open(rsp_path.clone())?;
-	let buflen: usize = if flen.try_into().unwrap_or(MAX_CHUNK_SIZE) > MAX_CHUNK_SIZE {
+	let rsp_path = HttpServer::get_path(&config, rsp_uri)?;
+	let flen = metadata(rsp_path.clone())?.len();
+	if flen.try_into().unwrap_or(MAX_CHUNK_SIZE + 1) > MAX_CHUNK_SIZE {
 		return Err(ErrorKind::InvalidRSPError(format!(
 			"RSPs are limited to {} bytes.",
 			MAX_CHUNK_SIZE
 		))
 		.into());
-	} else {
-		flen.try_into().unwrap_or(MAX_CHUNK_SIZE)
-	};
+	}
 
-	let mut buf = vec![0; (buflen + MAX_ESCAPE_SEQUENCE) as usize];
-	let mut first_loop = true;
+	let mut buf = vec![];
+	File::open(rsp_path.clone())?.read_to_end(&mut buf)?;
+	// parse the entire RSP before writing anything so that invalid RSPs result in an error
+	let nodes = parse_rsp(&buf)?;
+
+	// request used to evaluate expression tags
+	let mut expr_request = RustletRequest::new(
+		uri.to_string(),
+		query.to_string(),
+		vec![],
+		method.clone(),
+		version.clone(),
+		config.clone(),
+		headers.clone(),
+		keep_alive,
+		state.session_map.clone(),
+	);
+	expr_request.set_connection_id(wh.get_connection_id());
+	let mut session_id: Option<u128> = match expr_request.get_cookie("rustletsessionid")? {
+		Some(rsessionid) => rsessionid.parse().ok(),
+		None => None,
+	};
+	match session_id {
+		Some(session_id) => expr_request.set_session_id(session_id)?,
+		None => {}
+	}
 
-	loop {
-		let amt = file.read(&mut buf[0..buflen])?;
-		if first_loop {
-			HttpServer::write_headers(&wh, &config, true, false, keep_alive, vec![], None)?;
-			let mut callback_state = nioruntime_util::lockw!(wh.callback_state);
-			match keep_alive {
-				true => *callback_state = State::HeadersChunked,
-				false => *callback_state = State::HeadersClose,
-			}
+	// the response for the entire page. Embedded rustlets write to it, so the headers are
+	// written on the first flush, and rustlets may add headers until then.
+	let mut page_response = RustletResponse::new(
+		conn_data_is_async.clone(),
+		wh.clone(),
+		config.clone(),
+		keep_alive,
+		true,
+	);
+	page_response.set_content_type(rsp_content_type(rsp_uri))?;
+	match status {
+		Some(status) => page_response.set_status(status)?,
+		None => {}
+	}
+	for node in &nodes {
+		match node {
+			RspNode::Page(page_attrs) => apply_rsp_page(page_attrs, &mut page_response)?,
+			_ => {}
 		}
+	}
 
-		let mut start = 0;
-		let mut end;
-		loop {
-			end = amt;
-			for i in (start + 2)..amt {
-				if buf[i] == '=' as u8 && buf[i - 1] == '@' as u8 && buf[i - 2] == '<' as u8 {
-					// we have begun an escape sequence
-					end = i - 2;
-					break;
-				}
-			}
-			let wlen = end - start;
-			if keep_alive {
-				let msg_len_bytes = format!("{:X}\r\n", wlen);
-				let msg_len_bytes = msg_len_bytes.as_bytes();
-				wh.write(&msg_len_bytes[0..msg_len_bytes.len()])?;
-				wh.write(&buf[start..end])?;
-				if flen <= end.try_into().unwrap_or(0) {
-					wh.write(&("\r\n0\r\n\r\n".as_bytes())[0..7])?;
-					if !keep_alive {
-						wh.close()?;
-					}
-				} else {
-					wh.write(&("\r\n".as_bytes())[0..2])?;
-				}
-			} else {
-				if flen <= end.try_into().unwrap_or(0) {
-					wh.write(&buf[start..end])?;
-					if !keep_alive {
-						wh.close()?;
-					}
-				} else {
-					wh.write(&buf[start..end])?;
-				}
-			}
+	// the embedded rustlets may use the session and the CSRF token is stored in the
+	// session, so make sure there is one. It must be created before anything is written, as
+	// the cookie can't be set once a rustlet has flushed the headers.
+	if session_id.is_none() && rsp_needs_session(&nodes) {
+		let id: u128 = rand::random();
+		page_response.set_cookie("rustletsessionid", &format!("{}", id), "path=/")?;
+		expr_request.set_session_id(id)?;
+		session_id = Some(id);
+	}
+	let has_session = session_id.is_some();
+
+	let rsp_response = page_response.clone();
+	let request_attrs = expr_request.get_attrs();
+	let mut exec = |rustlet_name: &str, mut rsp_context: RspContext| {
+		// all tags of the RSP share the attributes and the session of the request
+		rsp_context.request_attrs = Some(request_attrs.clone());
+		rsp_context.session_id = session_id;
+		execute_rustlet(
+			conn_data_is_async.clone(),
+			rustlet_name,
+			conn_data,
+			has_content,
+			start_content,
+			end_content,
+			method.clone(),
+			config.clone(),
+			wh.clone(),
+			version.clone(),
+			uri,
+			query,
+			headers.clone(),
+			keep_alive,
+			Some(rsp_response.clone()),
+			state,
+			rsp_context,
+			None,
+		)
+	};
 
-			if end == amt {
-				break;
-			} else {
-				// find the end of the escape sequence
-				for i in end + 3..(amt - 1) {
-					if buf[i] == '>' as u8 {
-						let rustlet_name = std::str::from_utf8(&buf[(end + 3)..i])?;
-						execute_rustlet(
-							conn_data_is_async.clone(),
-							rustlet_name,
-							conn_data,
-							has_content,
-							start_content,
-							end_content,
-							method.clone(),
-							config.clone(),
-							wh.clone(),
-							version.clone(),
-							uri,
-							query,
-							headers.clone(),
-							keep_alive,
-							true,
-							session_map.clone(),
-						)?;
-						start = i + 1;
-						break;
+	let filters = {
+		let rustlets = nioruntime_util::lockr!(state.rustlets);
+		rustlets.filters.clone()
+	};
+	let (proceed, filters_run) =
+		run_filters_before(&filters, uri, &mut expr_request, &mut page_response)?;
+
+	if proceed {
+		render_rsp(
+			&nodes,
+			&mut page_response,
+			&mut expr_request,
+			has_session,
+			&None,
+			&mut exec,
+		)?;
+	}
+
+	run_filters_after(
+		&filters,
+		&filters_run,
+		&mut expr_request,
+		&mut page_response,
+	)?;
+
+	page_response.chained = false;
+	page_response.complete()?;
+
+	Ok(())
+}
+
+// run the before phase of the filters that match the uri, in the order they were added.
+// Returns whether processing should continue and the indices of the filters that were run.
+fn run_filters_before(
+	filters: &[Arc<FilterHolder>],
+	uri: &str,
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+) -> Result<(bool, Vec<usize>), Error> {
+	let mut filters_run = vec![];
+	for (i, holder) in filters.iter().enumerate() {
+		if !filter_matches(&holder.pattern, uri) {
+			continue;
+		}
+
+		filters_run.push(i);
+		match (holder.filter)(request, response, FilterPhase::Before)? {
+			FilterAction::Continue => {}
+			FilterAction::Stop => return Ok((false, filters_run)),
+		}
+	}
+
+	Ok((true, filters_run))
+}
+
+// run the after phase of the specified filters in reverse order.
+fn run_filters_after(
+	filters: &[Arc<FilterHolder>],
+	filters_run: &Vec<usize>,
+	request: &mut RustletRequest,
+	response: &mut RustletResponse,
+) -> Result<(), Error> {
+	for i in filters_run.iter().rev() {
+		match filters.get(*i) {
+			Some(holder) => {
+				(holder.filter)(request, response, FilterPhase::After)?;
+			}
+			None => {}
+		}
+	}
+
+	Ok(())
+}
+
+// returns true if the uri matches the filter pattern. Patterns that contain '*' or '?' are
+// globs that must match the entire uri. All other patterns match the uri itself and the uris
+// below it, so '/admin' matches '/admin' and '/admin/users' but not '/administrator'.
+pub(crate) fn filter_matches(pattern: &str, uri: &str) -> bool {
+	if pattern.contains('*') || pattern.contains('?') {
+		glob_matches(pattern.as_bytes(), uri.as_bytes())
+	} else {
+		match uri.strip_prefix(pattern) {
+			Some(rest) => pattern.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+			None => false,
+		}
+	}
+}
+
+// match a glob where '*' matches any sequence of characters and '?' matches any single
+// character.
+pub(crate) fn glob_matches(pattern: &[u8], value: &[u8]) -> bool {
+	let mut p = 0;
+	let mut v = 0;
+	let mut star: Option<(usize, usize)> = None;
+
+	while v < value.len() {
+		if p < pattern.len() && (pattern[p] == '?' as u8 || pattern[p] == value[v]) {
+			p += 1;
+			v += 1;
+		} else if p < pattern.len() && pattern[p] == '*' as u8 {
+			star = Some((p, v));
+			p += 1;
+		} else {
+			match star {
+				Some((star_p, star_v)) => {
+					// let the last '*' match one more character
+					star = Some((star_p, star_v + 1));
+					p = star_p + 1;
+					v = star_v + 1;
+				}
+				None => return false,
+			}
+		}
+	}
+
+	while p < pattern.len() && pattern[p] == '*' as u8 {
+		p += 1;
+	}
+
+	p == pattern.len()
+}
+
+// returns true if the RSP contains tags that may use the session, which are rustlets and
+// <@csrf> tags.
+fn rsp_needs_session(nodes: &Vec<RspNode>) -> bool {
+	nodes.iter().any(|node| match node {
+		RspNode::Csrf
+		| RspNode::Rustlet(_, _)
+		| RspNode::If(_, _, _, _)
+		| RspNode::For(_, _, _) => true,
+		_ => false,
+	})
+}
+
+// the default content type of an RSP, based on its file name. For example, 'data.json.rsp'
+// is served as 'application/json'.
+fn rsp_content_type(uri: &str) -> &'static str {
+	let uri = uri.to_lowercase();
+	let name = uri.trim_end_matches(".rsp");
+	let extension = match name.rfind('.') {
+		Some(pos) if pos > name.rfind('/').unwrap_or(0) => &name[(pos + 1)..],
+		_ => "",
+	};
+
+	match extension {
+		"json" => "application/json",
+		"xml" => "application/xml",
+		"css" => "text/css",
+		"js" => "application/javascript",
+		"txt" => "text/plain",
+		"csv" => "text/csv",
+		"svg" => "image/svg+xml",
+		_ => "text/html",
+	}
+}
+
+// apply the attributes of a <@page> directive to the response of the RSP.
+fn apply_rsp_page(
+	page_attrs: &HashMap<String, String>,
+	response: &mut RustletResponse,
+) -> Result<(), Error> {
+	match page_attrs.get("content_type") {
+		Some(content_type) => response.set_content_type(content_type)?,
+		None => {}
+	}
+	match page_attrs.get("status") {
+		Some(status) => match status.parse() {
+			Ok(status) if status >= 100 && status <= 999 => response.set_status(status)?,
+			_ => {
+				return Err(ErrorKind::InvalidRSPError(format!(
+					"invalid status in <@page>: '{}'",
+					status
+				))
+				.into());
+			}
+		},
+		None => {}
+	}
+
+	Ok(())
+}
+
+// a parsed element of an RSP
+enum RspNode {
+	Static(Vec<u8>),                                                 // static data
+	Rustlet(String, HashMap<String, String>),                        // <@=name attrs>
+	Expression(String, bool),                                        // <@:expr> or <@!expr>
+	If(String, HashMap<String, String>, Vec<RspNode>, Vec<RspNode>), // <@if>..<@else>..<@end>
+	For(String, HashMap<String, String>, Vec<RspNode>),              // <@for>..<@end>
+	Page(HashMap<String, String>),                                   // <@page attrs>
+	Csrf,                                                            // <@csrf>
+}
+
+// a block (<@if> or <@for>) that is in the process of being parsed
+struct RspBlock {
+	node: RspNode,
+	nodes: Vec<RspNode>,
+	in_else: bool,
+	line: usize,
+}
+
+// execute the parsed nodes of an RSP. Rustlets are executed via the exec closure. Output is
+// buffered in the response until a rustlet flushes, so headers may be added until then.
+fn render_rsp(
+	nodes: &Vec<RspNode>,
+	response: &mut RustletResponse,
+	expr_request: &mut RustletRequest,
+	has_session: bool,
+	loop_iteration: &Option<(usize, String)>,
+	exec: &mut dyn FnMut(&str, RspContext) -> Result<(), Error>,
+) -> Result<(), Error> {
+	for node in nodes {
+		match node {
+			RspNode::Static(data) => response.write(data)?,
+			RspNode::Rustlet(rustlet_name, tag_attrs) => {
+				exec(
+					rustlet_name.as_str(),
+					RspContext::new(tag_attrs.clone(), loop_iteration.clone()),
+				)?;
+			}
+			RspNode::Expression(expr, escape) => {
+				let value =
+					evaluate_rsp_expression(expr, expr_request, has_session, loop_iteration)?;
+				let value = match *escape {
+					true => html_escape(&value),
+					false => value,
+				};
+				response.write(value.as_bytes())?;
+			}
+			RspNode::If(rustlet_name, tag_attrs, if_nodes, else_nodes) => {
+				let rsp_context = RspContext::new(tag_attrs.clone(), loop_iteration.clone());
+				exec(rustlet_name.as_str(), rsp_context.clone())?;
+				let condition = *nioruntime_util::lockr!(rsp_context.condition);
+				render_rsp(
+					match condition {
+						true => if_nodes,
+						false => else_nodes,
+					},
+					response,
+					expr_request,
+					has_session,
+					loop_iteration,
+					exec,
+				)?;
+			}
+			RspNode::For(rustlet_name, tag_attrs, for_nodes) => {
+				let rsp_context = RspContext::new(tag_attrs.clone(), loop_iteration.clone());
+				exec(rustlet_name.as_str(), rsp_context.clone())?;
+				let items = {
+					let items = nioruntime_util::lockr!(rsp_context.loop_items);
+					items.clone()
+				};
+				for (index, item) in items.into_iter().enumerate() {
+					render_rsp(
+						for_nodes,
+						response,
+						expr_request,
+						has_session,
+						&Some((index, item)),
+						exec,
+					)?;
+				}
+			}
+			RspNode::Page(_) => {} // applied before rendering
+			RspNode::Csrf => {
+				let token = get_or_create_token(expr_request)?;
+				response.write(hidden_input(&token).as_bytes())?;
+			}
+		}
+	}
+
+	Ok(())
+}
+
+// parse an RSP into its static data and tags.
+fn parse_rsp(buf: &[u8]) -> Result<Vec<RspNode>, Error> {
+	let mut root = vec![];
+	let mut blocks: Vec<RspBlock> = vec![];
+	let len = buf.len();
+	let mut start = 0;
+	let mut i = 0;
+
+	while i + 1 < len {
+		if buf[i] != '<' as u8 || buf[i + 1] != '@' as u8 || !is_rsp_tag(&buf[(i + 2)..]) {
+			i += 1;
+			continue;
+		}
+
+		let line = buf[0..i].iter().filter(|c| **c == '\n' as u8).count() + 1;
+		let tag_end = match find_rsp_tag_end(buf, i + 2) {
+			Some(tag_end) => tag_end,
+			None => {
+				return Err(ErrorKind::InvalidRSPError(format!(
+					"non-terminated escape sequence in RSP on line {}",
+					line
+				))
+				.into());
+			}
+		};
+
+		{
+			let nodes = match blocks.last_mut() {
+				Some(block) => &mut block.nodes,
+				None => &mut root,
+			};
+			if start < i {
+				nodes.push(RspNode::Static(buf[start..i].to_vec()));
+			}
+		}
+
+		let tag = std::str::from_utf8(&buf[(i + 2)..tag_end])?;
+		let first = tag.chars().next().unwrap_or(' ');
+		let node = if first == '=' {
+			let (rustlet_name, tag_attrs) = parse_rsp_tag(&tag[1..])?;
+			Some(RspNode::Rustlet(rustlet_name, tag_attrs))
+		} else if first == ':' || first == '!' {
+			Some(RspNode::Expression(
+				tag[1..].trim().to_string(),
+				first == ':',
+			))
+		} else {
+			let (keyword, mut tag_attrs) = parse_rsp_tag(tag)?;
+			match keyword.as_str() {
+				"if" | "for" => {
+					let rustlet_name = match tag_attrs.remove("rustlet") {
+						Some(rustlet_name) => rustlet_name,
+						None => {
+							return Err(ErrorKind::InvalidRSPError(format!(
+								"<@{}> on line {} has no rustlet attribute",
+								keyword, line
+							))
+							.into());
+						}
+					};
+					let node = match keyword.as_str() {
+						"if" => RspNode::If(rustlet_name, tag_attrs, vec![], vec![]),
+						_ => RspNode::For(rustlet_name, tag_attrs, vec![]),
+					};
+					blocks.push(RspBlock {
+						node,
+						nodes: vec![],
+						in_else: false,
+						line,
+					});
+					None
+				}
+				"page" => {
+					if blocks.len() > 0 {
+						return Err(ErrorKind::InvalidRSPError(format!(
+							"<@page> on line {} must not be inside a block",
+							line
+						))
+						.into());
 					}
+					Some(RspNode::Page(tag_attrs))
 				}
-				if start < end {
-					// error we didn't find the end of it
-					// TODO: handle chunk overlapping escape sequences
-					// TODO: handle invalid RSP better, show linenum, etc
-					return Err(ErrorKind::InvalidRSPError(
-						"non-terminated escape sequence in RSP".to_string(),
-					)
-					.into());
+				"csrf" => Some(RspNode::Csrf),
+				"else" => {
+					match blocks.last_mut() {
+						Some(block) => match &mut block.node {
+							RspNode::If(_, _, if_nodes, _) if !block.in_else => {
+								*if_nodes = std::mem::replace(&mut block.nodes, vec![]);
+								block.in_else = true;
+							}
+							_ => {
+								return Err(ErrorKind::InvalidRSPError(format!(
+									"unexpected <@else> on line {}",
+									line
+								))
+								.into());
+							}
+						},
+						None => {
+							return Err(ErrorKind::InvalidRSPError(format!(
+								"unexpected <@else> on line {}",
+								line
+							))
+							.into());
+						}
+					}
+					None
+				}
+				_ => {
+					// "end"
+					let mut block = match blocks.pop() {
+						Some(block) => block,
+						None => {
+							return Err(ErrorKind::InvalidRSPError(format!(
+								"unexpected <@end> on line {}",
+								line
+							))
+							.into());
+						}
+					};
+					let nodes = std::mem::replace(&mut block.nodes, vec![]);
+					match &mut block.node {
+						RspNode::If(_, _, if_nodes, else_nodes) => match block.in_else {
+							true => *else_nodes = nodes,
+							false => *if_nodes = nodes,
+						},
+						RspNode::For(_, _, for_nodes) => *for_nodes = nodes,
+						_ => {}
+					}
+					Some(block.node)
 				}
 			}
+		};
+
+		match node {
+			Some(node) => match blocks.last_mut() {
+				Some(block) => block.nodes.push(node),
+				None => root.push(node),
+			},
+			None => {}
 		}
-		flen -= amt.try_into().unwrap_or(0);
 
-		if flen <= 0 {
-			break;
+		i = tag_end + 1;
+		start = i;
+	}
+
+	match blocks.last() {
+		Some(block) => {
+			return Err(ErrorKind::InvalidRSPError(format!(
+				"block on line {} has no matching <@end>",
+				block.line
+			))
+			.into());
 		}
-		first_loop = false;
+		None => {}
 	}
 
-	Ok(())
+	if start < len {
+		root.push(RspNode::Static(buf[start..len].to_vec()));
+	}
+
+	Ok(root)
+}
+
+// returns true if the data following '<@' is an RSP tag.
+fn is_rsp_tag(data: &[u8]) -> bool {
+	match data.first() {
+		Some(c) if *c == '=' as u8 || *c == ':' as u8 || *c == '!' as u8 => true,
+		_ => RSP_KEYWORDS.iter().any(|keyword| {
+			let keyword = keyword.as_bytes();
+			data.len() > keyword.len()
+				&& &data[0..keyword.len()] == keyword
+				&& (data[keyword.len()] == '>' as u8
+					|| (data[keyword.len()] as char).is_whitespace())
+		}),
+	}
 }
 
+// find the '>' that terminates the tag starting at the specified offset. A '>' inside a
+// quoted attribute value does not terminate the tag.
+fn find_rsp_tag_end(buf: &[u8], offset: usize) -> Option<usize> {
+	let mut quote: Option<u8> = None;
+	for i in offset..buf.len() {
+		match quote {
+			Some(q) => {
+				if buf[i] == q {
+					quote = None;
+				}
+			}
+			None => {
+				if buf[i] == '"' as u8 || buf[i] == '\'' as u8 {
+					quote = Some(buf[i]);
+				} else if buf[i] == '>' as u8 {
+					return Some(i);
+				}
+			}
+		}
+	}
+
+	None
+}
+
+/// Escape the specified string for inclusion in an HTML document. The characters
+/// `&`, `<`, `>`, `"` and `'` are replaced with their corresponding HTML entities.
+pub fn html_escape(value: &str) -> String {
+	let mut ret = String::with_capacity(value.len());
+	for c in value.chars() {
+		match c {
+			'&' => ret.push_str("&amp;"),
+			'<' => ret.push_str("&lt;"),
+			'>' => ret.push_str("&gt;"),
+			'"' => ret.push_str("&quot;"),
+			'\'' => ret.push_str("&#x27;"),
+			_ => ret.push(c),
+		}
+	}
+	ret
+}
+
+// evaluate an RSP expression tag such as `<@:query.name>`, `<@:header.User-Agent>`,
+// `<@:cookie.name>`, `<@:session.user>`, `<@:attr.user>` or `<@:loop.value>`. Missing values
+// evaluate to an empty string.
+fn evaluate_rsp_expression(
+	expr: &str,
+	request: &mut RustletRequest,
+	has_session: bool,
+	loop_iteration: &Option<(usize, String)>,
+) -> Result<String, Error> {
+	let expr = expr.trim();
+	let (scope, name) = match expr.find('.') {
+		Some(pos) => (&expr[..pos], &expr[(pos + 1)..]),
+		None => {
+			return Err(
+				ErrorKind::InvalidRSPError(format!("invalid RSP expression '{}'", expr)).into(),
+			);
+		}
+	};
+
+	let value = match scope {
+		"query" => request.get_query_parameter(name)?,
+		"header" => request.get_header(name)?,
+		"cookie" => request.get_cookie(name)?,
+		"session" => match has_session {
+			true => request
+				.get_session::<SessionString>(name)?
+				.map(|value| value.0),
+			false => None,
+		},
+		"attr" => request.get_attr::<String>(name)?,
+		"loop" => match loop_iteration {
+			Some((index, value)) => match name {
+				"index" => Some(format!("{}", index)),
+				"value" => Some(value.clone()),
+				_ => None,
+			},
+			None => None,
+		},
+		_ => {
+			return Err(ErrorKind::InvalidRSPError(format!(
+				"unknown scope '{}' in RSP expression '{}'",
+				scope, expr
+			))
+			.into());
+		}
+	};
+
+	Ok(value.unwrap_or("".to_string()))
+}
+
+// parse the contents of an RSP tag (the text between '<@=' and '>') into the rustlet name
+// and its attributes. For example: `widget limit="5" title='Latest'`.
+fn parse_rsp_tag(tag: &str) -> Result<(String, HashMap<String, String>), Error> {
+	let tag = tag.trim();
+	let (name, mut rem) = match tag.find(char::is_whitespace) {
+		Some(pos) => (&tag[..pos], tag[pos..].trim_start()),
+		None => (tag, ""),
+	};
+
+	if name.len() == 0 {
+		return Err(ErrorKind::InvalidRSPError("RSP tag has no rustlet name".to_string()).into());
+	}
+
+	let mut attrs = HashMap::new();
+	while rem.len() > 0 {
+		let eq = match rem.find('=') {
+			Some(eq) => eq,
+			None => {
+				return Err(ErrorKind::InvalidRSPError(format!(
+					"attribute '{}' in RSP tag '{}' has no value",
+					rem, name
+				))
+				.into());
+			}
+		};
+		let key = rem[..eq].trim();
+		if key.len() == 0 || key.contains(char::is_whitespace) {
+			return Err(ErrorKind::InvalidRSPError(format!(
+				"invalid attribute name '{}' in RSP tag '{}'",
+				key, name
+			))
+			.into());
+		}
+
+		let value_start = rem[(eq + 1)..].trim_start();
+		let (value, next) = match value_start.chars().next() {
+			Some(q) if q == '"' || q == '\'' => match value_start[1..].find(q) {
+				Some(close) => (&value_start[1..(close + 1)], &value_start[(close + 2)..]),
+				None => {
+					return Err(ErrorKind::InvalidRSPError(format!(
+						"non-terminated value for attribute '{}' in RSP tag '{}'",
+						key, name
+					))
+					.into());
+				}
+			},
+			_ => match value_start.find(char::is_whitespace) {
+				Some(pos) => (&value_start[..pos], &value_start[pos..]),
+				None => (value_start, ""),
+			},
+		};
+
+		attrs.insert(key.to_string(), value.to_string());
+		rem = next.trim_start();
+	}
+
+	Ok((name.to_string(), attrs))
+}
+
+/// A rustlet container. Each container has its own HTTP server, rustlets, mappings, filters,
+/// sessions and configuration, so multiple containers may run in the same process, for
+/// example on different ports. The macros use a default container, see
+/// [`crate::rustlet_init`]. Other containers are created via [`RustletContainer::builder`].
+/// Clones of a container share its state.
+#[derive(Clone)]
 pub struct RustletContainer {
-	config: Option<RustletConfig>,
-	http: Option<HttpServer>,
+	state: Arc<ContainerState>,
 }
 
 impl RustletContainer {
 	pub fn new() -> Self {
 		RustletContainer {
-			config: None,
-			http: None,
+			state: Arc::new(ContainerState::new()),
 		}
 	}
 
+	/// Returns a builder for a container that is independent of the default container.
+	pub fn builder() -> RustletContainerBuilder {
+		RustletContainerBuilder::new()
+	}
+
 	pub fn set_config(&mut self, config: RustletConfig) -> Result<(), Error> {
 		let http = HttpServer::new(config.http_config.clone());
-		self.config = Some(config.clone());
-		self.http = Some(http);
-		let mut static_config = nioruntime_util::lockw!(RUSTLET_CONFIG);
+		{
+			let mut state_http = nioruntime_util::lockw!(self.state.http);
+			*state_http = Some(http);
+		}
+		// rate limits come first so that rejected requests are as cheap as possible
+		for rate_limit in &config.rate_limits {
+			let rate_limiter = self.state.limits.add_rate_limiter(rate_limit.clone())?;
+			self.add_filter(
+				&format!("rustlet.rate_limit.{}", rate_limit.pattern),
+				&rate_limit.pattern,
+				move |request, response, phase| {
+					rate_limit_filter(&rate_limiter, request, response, phase)
+				},
+			)?;
+		}
 
-		*static_config = Some(config);
+		// CORS comes next as preflight requests are not authenticated and so that rejected
+		// requests still have the CORS headers. A single filter selects the first matching
+		// configuration.
+		if config.cors.len() > 0 {
+			let cors_configs = config.cors.clone();
+			self.add_filter("rustlet.cors", "/", move |request, response, phase| {
+				cors_filter(&cors_configs, request, response, phase)
+			})?;
+		}
+
+		for auth_config in &config.auth {
+			let auth_config = auth_config.clone();
+			self.add_filter(
+				&format!("rustlet.auth.{}", auth_config.pattern),
+				&auth_config.pattern.clone(),
+				move |request, response, phase| auth_filter(&auth_config, request, response, phase),
+			)?;
+		}
+
+		match &config.csrf {
+			Some(csrf_config) => {
+				self.add_filter("rustlet.csrf", &csrf_config.pattern, csrf_filter)?;
+			}
+			None => {}
+		}
+
+		let mut state_config = nioruntime_util::lockw!(self.state.config);
+
+		*state_config = Some(config);
 
 		Ok(())
 	}
 
 	pub fn start(&mut self) -> Result<(), Error> {
-		let http = self.http.as_mut();
-		match http {
+		let session_file = {
+			let config = nioruntime_util::lockr!(self.state.config);
+			config
+				.as_ref()
+				.and_then(|config| config.session_file.clone())
+		};
+		match session_file {
+			Some(session_file) => load_sessions(&self.state, &session_file)?,
+			None => {}
+		}
+		self.state.stopping.store(false, Ordering::SeqCst);
+
+		let mut http = nioruntime_util::lockw!(self.state.http);
+		match http.as_mut() {
 			Some(mut http) => {
+				let key = container_key(&http.config);
+				{
+					let mut containers = nioruntime_util::lockw!(CONTAINERS);
+					if containers.contains_key(&key) {
+						return Err(ErrorKind::SetupError(format!(
+							"a rustlet container is already started on {}",
+							key
+						))
+						.into());
+					}
+					containers.insert(key.clone(), self.state.clone());
+				}
+
 				http.config.callback = api_callback;
 				http.config.on_panic = on_panic;
-				http.config.on_housekeeper = housekeeper;
-				http.start()?;
+				let res = acquire_housekeeper(&self.state).and_then(|housekeeper| {
+					http.config.on_housekeeper = housekeeper;
+					http.start()
+				});
+				match res {
+					Ok(_) => {}
+					Err(e) => {
+						release_housekeeper(&self.state)?;
+						let mut containers = nioruntime_util::lockw!(CONTAINERS);
+						containers.remove(&key);
+						return Err(e);
+					}
+				}
 				http.add_api_extension("rsp".to_string())?;
+
+				let plugins = {
+					let config = nioruntime_util::lockr!(self.state.config);
+					config
+						.as_ref()
+						.map(|config| config.plugins)
+						.unwrap_or(false)
+				};
+				if plugins {
+					let mut state_plugins = nioruntime_util::lockw!(self.state.plugins);
+					*state_plugins = Some(Plugins::new(&http.config.root_dir));
+				}
 			}
 			None => {
 				log_multi!(
@@ -1137,23 +3011,141 @@ impl RustletContainer {
 				);
 			}
 		}
+		drop(http);
+
+		check_plugins(&self.state, true)
+	}
+
+	pub fn stop(&self, timeout: Duration) -> Result<(), Error> {
+		log_multi!(INFO, MAIN_LOG, "Stopping rustlet container");
+		self.state.stopping.store(true, Ordering::SeqCst);
+
+		// wait for in-flight requests and async rustlets
+		let start = Instant::now();
+		loop {
+			let in_flight = self.state.in_flight.load(Ordering::SeqCst);
+			let async_in_flight = self.state.async_in_flight.load(Ordering::SeqCst);
+			if in_flight == 0 && async_in_flight == 0 {
+				break;
+			}
+			if start.elapsed() >= timeout {
+				log_multi!(
+					WARN,
+					MAIN_LOG,
+					"timed out waiting for {} request(s) and {} async rustlet(s)",
+					in_flight,
+					async_in_flight
+				);
+				break;
+			}
+			std::thread::sleep(Duration::from_millis(10));
+		}
+
+		{
+			let mut http = nioruntime_util::lockw!(self.state.http);
+			match http.as_mut() {
+				Some(http) => {
+					http.stop()?;
+					release_housekeeper(&self.state)?;
+					let mut containers = nioruntime_util::lockw!(CONTAINERS);
+					containers.remove(&container_key(&http.config));
+				}
+				None => {}
+			}
+		}
+
+		// give the HTTP server the time to write the remaining entries of the request log
+		std::thread::sleep(REQUEST_LOG_FLUSH_WAIT);
+
+		// write the remaining limit hits to the stats log
+		self.state.limits.housekeep()?;
 
+		let session_file = {
+			let config = nioruntime_util::lockr!(self.state.config);
+			config
+				.as_ref()
+				.and_then(|config| config.session_file.clone())
+		};
+		match session_file {
+			Some(session_file) => save_sessions(&self.state, &session_file)?,
+			None => {}
+		}
+
+		log_multi!(INFO, MAIN_LOG, "Rustlet container stopped");
 		Ok(())
 	}
 
 	pub fn add_rustlet(&mut self, name: &str, rustlet: Rustlet) -> Result<(), Error> {
-		let mut rustlets = nioruntime_util::lockw!(RUSTLETS);
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
 		rustlets
 			.rustlets
-			.insert(name.to_string(), Box::pin(rustlet));
+			.insert(name.to_string(), Arc::new(rustlet));
+
+		Ok(())
+	}
+
+	pub fn set_error_handler(&mut self, name: &str) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		rustlets.error_handler = Some(name.to_string());
 
 		Ok(())
 	}
 
+	pub fn set_not_found(&mut self, name: &str) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		rustlets.not_found = Some(name.to_string());
+
+		Ok(())
+	}
+
+	pub fn add_filter<F>(&mut self, name: &str, pattern: &str, filter: F) -> Result<(), Error>
+	where
+		F: Fn(
+				&mut RustletRequest,
+				&mut RustletResponse,
+				FilterPhase,
+			) -> Result<FilterAction, Error>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		// make the request and response available to the macros used within the filter
+		let filter: RustletFilter = Box::pin(
+			move |request: &mut RustletRequest,
+			      response: &mut RustletResponse,
+			      phase: FilterPhase| {
+				crate::macros::LOCALRUSTLET.with(|f| {
+					*f.borrow_mut() = Some(((*request).clone(), (*response).clone()));
+				});
+				(filter)(request, response, phase)
+			},
+		);
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		let holder = Arc::new(FilterHolder {
+			name: name.to_string(),
+			pattern: pattern.to_string(),
+			filter: Box::pin(filter),
+		});
+
+		// a filter with the same name is replaced, keeping its position in the chain
+		match rustlets.filters.iter().position(|f| f.name == name) {
+			Some(i) => rustlets.filters[i] = holder,
+			None => rustlets.filters.push(holder),
+		}
+
+		Ok(())
+	}
+
+	pub fn get_panic_count(&self, name: &str) -> Result<u64, Error> {
+		let panic_counts = nioruntime_util::lockr!(self.state.panic_counts);
+		Ok(*panic_counts.get(name).unwrap_or(&0))
+	}
+
 	pub fn add_rustlet_mapping(&mut self, path: &str, name: &str) -> Result<(), Error> {
-		let mut rustlets = nioruntime_util::lockw!(RUSTLETS);
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		let http = nioruntime_util::lockr!(self.state.http);
 
-		match self.http.as_ref() {
+		match http.as_ref() {
 			Some(http) => {
 				http.add_api_mapping(path.to_string())?;
 				rustlets.mappings.insert(path.to_string(), name.to_string());
@@ -1169,4 +3161,674 @@ impl RustletContainer {
 
 		Ok(())
 	}
+
+	pub fn add_host_rustlet_mapping(
+		&mut self,
+		host: &str,
+		path: &str,
+		name: &str,
+	) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		let http = nioruntime_util::lockr!(self.state.http);
+
+		match http.as_ref() {
+			Some(http) => {
+				http.add_api_mapping(path.to_string())?;
+				rustlets
+					.host_mappings
+					.entry(host.trim().to_lowercase())
+					.or_insert(HashMap::new())
+					.insert(path.to_string(), name.to_string());
+			}
+			None => {
+				log_multi!(
+					ERROR,
+					MAIN_LOG,
+					"Couldn't add rustlet mapping: Configuration not found"
+				);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Removes the rustlet with the specified name and the mappings to it, including the
+	/// mappings of virtual hosts. Requests for these uris receive a '404 Not Found' response.
+	pub fn remove_rustlet(&mut self, name: &str) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		if rustlets.rustlets.remove(name).is_none() {
+			return Err(ErrorKind::SetupError(format!("rustlet '{}' does not exist", name)).into());
+		}
+		rustlets.mappings.retain(|_, target| *target != name);
+		for host_mappings in rustlets.host_mappings.values_mut() {
+			host_mappings.retain(|_, target| *target != name);
+		}
+		rustlets
+			.host_mappings
+			.retain(|_, host_mappings| !host_mappings.is_empty());
+		Ok(())
+	}
+
+	/// Removes the mapping of the specified path.
+	pub fn remove_rustlet_mapping(&mut self, path: &str) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		match rustlets.mappings.remove(path) {
+			Some(_) => Ok(()),
+			None => Err(ErrorKind::SetupError(format!("'{}' is not mapped", path)).into()),
+		}
+	}
+
+	/// Removes the mapping of the specified path of a virtual host.
+	pub fn remove_host_rustlet_mapping(&mut self, host: &str, path: &str) -> Result<(), Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		let host = host.trim().to_lowercase();
+		let removed = match rustlets.host_mappings.get_mut(&host) {
+			Some(host_mappings) => host_mappings.remove(path).is_some(),
+			None => false,
+		};
+		match rustlets.host_mappings.get(&host) {
+			Some(host_mappings) if host_mappings.is_empty() => {
+				rustlets.host_mappings.remove(&host);
+			}
+			_ => {}
+		}
+		match removed {
+			true => Ok(()),
+			false => Err(ErrorKind::SetupError(format!(
+				"'{}' is not mapped for host '{}'",
+				path, host
+			))
+			.into()),
+		}
+	}
+
+	/// Maps an already mapped path to another rustlet and returns the name of the rustlet
+	/// it was mapped to. Each request is processed by either the old or the new rustlet.
+	pub fn swap_rustlet_mapping(&mut self, path: &str, name: &str) -> Result<String, Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		if !rustlets.rustlets.contains_key(name) {
+			return Err(ErrorKind::SetupError(format!("rustlet '{}' does not exist", name)).into());
+		}
+		match rustlets.mappings.get_mut(path) {
+			Some(target) => Ok(std::mem::replace(target, name.to_string())),
+			None => Err(ErrorKind::SetupError(format!("'{}' is not mapped", path)).into()),
+		}
+	}
+
+	/// Maps an already mapped path of a virtual host to another rustlet and returns the name
+	/// of the rustlet it was mapped to.
+	pub fn swap_host_rustlet_mapping(
+		&mut self,
+		host: &str,
+		path: &str,
+		name: &str,
+	) -> Result<String, Error> {
+		let mut rustlets = nioruntime_util::lockw!(self.state.rustlets);
+		if !rustlets.rustlets.contains_key(name) {
+			return Err(ErrorKind::SetupError(format!("rustlet '{}' does not exist", name)).into());
+		}
+		let host = host.trim().to_lowercase();
+		match rustlets
+			.host_mappings
+			.get_mut(&host)
+			.and_then(|host_mappings| host_mappings.get_mut(path))
+		{
+			Some(target) => Ok(std::mem::replace(target, name.to_string())),
+			None => Err(ErrorKind::SetupError(format!(
+				"'{}' is not mapped for host '{}'",
+				path, host
+			))
+			.into()),
+		}
+	}
+
+	/// Returns the names of the rustlets, sorted.
+	pub fn get_rustlets(&self) -> Result<Vec<String>, Error> {
+		let rustlets = nioruntime_util::lockr!(self.state.rustlets);
+		let mut names: Vec<String> = rustlets.rustlets.keys().cloned().collect();
+		names.sort();
+		Ok(names)
+	}
+
+	/// Returns the mappings as (host, path, rustlet name), sorted. The host is None for
+	/// mappings that apply to all hosts.
+	pub fn get_rustlet_mappings(&self) -> Result<Vec<(Option<String>, String, String)>, Error> {
+		let rustlets = nioruntime_util::lockr!(self.state.rustlets);
+		let mut mappings: Vec<(Option<String>, String, String)> = rustlets
+			.mappings
+			.iter()
+			.map(|(path, name)| (None, path.clone(), name.clone()))
+			.collect();
+		for (host, host_mappings) in &rustlets.host_mappings {
+			for (path, name) in host_mappings {
+				mappings.push((Some(host.clone()), path.clone(), name.clone()));
+			}
+		}
+		mappings.sort();
+		Ok(mappings)
+	}
+
+	/// Add and map the rustlets declared with the [`crate::declaration::rustlet`] attribute.
+	/// This is called by [`crate::rustlet_init`]. The container must be started.
+	pub fn add_declared_rustlets(&mut self) -> Result<(), Error> {
+		for declaration in declared_rustlets() {
+			{
+				let rustlets = nioruntime_util::lockr!(self.state.rustlets);
+				if rustlets.rustlets.contains_key(declaration.name) {
+					return Err(ErrorKind::SetupError(format!(
+						"rustlet '{}' already exists",
+						declaration.name
+					))
+					.into());
+				}
+				match rustlets.mappings.get(declaration.path) {
+					Some(other) => {
+						return Err(ErrorKind::SetupError(format!(
+							"'{}' is declared by rustlet '{}', but is already mapped to '{}'",
+							declaration.path, declaration.name, other
+						))
+						.into())
+					}
+					None => {}
+				}
+			}
+			let handler = declaration.handler;
+			let method = declaration.method.clone();
+			let rustlet: Rustlet = Box::pin(
+				move |request: &mut RustletRequest, response: &mut RustletResponse| {
+					match &method {
+						Some(method) if *method != request.get_http_method()? => {
+							response.set_status(405)?;
+							response.add_header("Allow", method_name(method))?;
+							response.set_content_type("text/plain")?;
+							response.write("405 Method Not Allowed".as_bytes())?;
+							return Ok(());
+						}
+						_ => {}
+					}
+					crate::macros::LOCALRUSTLET.with(|f| {
+						*f.borrow_mut() = Some(((*request).clone(), (*response).clone()));
+					});
+					(handler)(request, response)
+				},
+			);
+			self.add_rustlet(declaration.name, rustlet)?;
+			self.add_rustlet_mapping(declaration.path, declaration.name)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// A builder of [`RustletContainer`]s. The container is configured, started and its
+/// rustlets, filters and mappings are added by [`RustletContainerBuilder::build`]. The
+/// macros that read the request and write the response, such as [`crate::request`] and
+/// [`crate::response`], may be used within the rustlets and filters of the container.
+///
+/// # Examples
+/// ```no_run
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use nioruntime_log::*;
+///
+/// debug!();
+///
+/// fn main() -> Result<(), Error> {
+///     let admin = RustletContainer::builder()
+///         .config(RustletConfig {
+///             http_config: HttpConfig {
+///                 port: 9090,
+///                 ..HttpConfig::default()
+///             },
+///             ..RustletConfig::default()
+///         })
+///         .rustlet("status", |_request, _response| {
+///             response!("ok");
+///             Ok(())
+///         })
+///         .mapping("/status", "status")
+///         .build()?;
+///
+///     Ok(())
+/// }
+/// ```
+pub struct RustletContainerBuilder {
+	config: RustletConfig,
+	rustlets: Vec<(String, Rustlet)>,
+	filters: Vec<(String, String, RustletFilter)>,
+	// (host, path, rustlet name)
+	mappings: Vec<(Option<String>, String, String)>,
+	not_found: Option<String>,
+	error_handler: Option<String>,
+}
+
+impl RustletContainerBuilder {
+	fn new() -> Self {
+		RustletContainerBuilder {
+			config: RustletConfig::default(),
+			rustlets: vec![],
+			filters: vec![],
+			mappings: vec![],
+			not_found: None,
+			error_handler: None,
+		}
+	}
+
+	/// Set the configuration of the container. The default value is
+	/// [`RustletConfig::default`].
+	pub fn config(mut self, config: RustletConfig) -> Self {
+		self.config = config;
+		self
+	}
+
+	/// Add a rustlet with the specified name.
+	pub fn rustlet<F>(mut self, name: &str, rustlet: F) -> Self
+	where
+		F: Fn(&mut RustletRequest, &mut RustletResponse) -> Result<(), Error>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		let rustlet: Rustlet = Box::pin(
+			move |request: &mut RustletRequest, response: &mut RustletResponse| {
+				crate::macros::LOCALRUSTLET.with(|f| {
+					*f.borrow_mut() = Some(((*request).clone(), (*response).clone()));
+				});
+				(rustlet)(request, response)
+			},
+		);
+		self.rustlets.push((name.to_string(), rustlet));
+		self
+	}
+
+	/// Add a filter, see [`RustletContainer::add_filter`].
+	pub fn filter<F>(mut self, name: &str, pattern: &str, filter: F) -> Self
+	where
+		F: Fn(
+				&mut RustletRequest,
+				&mut RustletResponse,
+				FilterPhase,
+			) -> Result<FilterAction, Error>
+			+ Send
+			+ Sync
+			+ 'static,
+	{
+		let filter: RustletFilter = Box::pin(filter);
+		self.filters
+			.push((name.to_string(), pattern.to_string(), filter));
+		self
+	}
+
+	/// Map the specified path to a rustlet.
+	pub fn mapping(mut self, path: &str, name: &str) -> Self {
+		self.mappings
+			.push((None, path.to_string(), name.to_string()));
+		self
+	}
+
+	/// Map the specified path of a virtual host to a rustlet.
+	pub fn host_mapping(mut self, host: &str, path: &str, name: &str) -> Self {
+		self.mappings
+			.push((Some(host.to_string()), path.to_string(), name.to_string()));
+		self
+	}
+
+	/// Set the rustlet or RSP that handles requests for uris that are not found.
+	pub fn not_found(mut self, name: &str) -> Self {
+		self.not_found = Some(name.to_string());
+		self
+	}
+
+	/// Set the rustlet that handles errors.
+	pub fn error_handler(mut self, name: &str) -> Self {
+		self.error_handler = Some(name.to_string());
+		self
+	}
+
+	/// Configure and start the container.
+	pub fn build(self) -> Result<RustletContainer, Error> {
+		let mut container = RustletContainer::new();
+		container.set_config(self.config)?;
+		container.start()?;
+		for (name, rustlet) in self.rustlets {
+			container.add_rustlet(&name, rustlet)?;
+		}
+		for (name, pattern, filter) in self.filters {
+			container.add_filter(&name, &pattern, move |request, response, phase| {
+				(filter)(request, response, phase)
+			})?;
+		}
+		for (host, path, name) in self.mappings {
+			match host {
+				Some(host) => container.add_host_rustlet_mapping(&host, &path, &name)?,
+				None => container.add_rustlet_mapping(&path, &name)?,
+			}
+		}
+		match self.not_found {
+			Some(not_found) => container.set_not_found(&not_found)?,
+			None => {}
+		}
+		match self.error_handler {
+			Some(error_handler) => container.set_error_handler(&error_handler)?,
+			None => {}
+		}
+		Ok(container)
+	}
+}
+
+// a GET request for '/' with the specified query, content and headers, which isn't sent to a
+// container.
+#[cfg(test)]
+pub(crate) fn test_request(
+	query: &str,
+	content: &[u8],
+	headers: Vec<(&str, &str)>,
+) -> RustletRequest {
+	RustletRequest::new(
+		"/".to_string(),
+		query.to_string(),
+		content.to_vec(),
+		HttpMethod::Get,
+		HttpVersion::V11,
+		HttpConfig::default(),
+		headers
+			.iter()
+			.map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+			.collect(),
+		false,
+		Arc::new(RwLock::new(HashMap::new())),
+	)
+}
+
+// sends a request to the container on the specified port and returns the response. The
+// request is sent as is, so it should ask the server to close the connection.
+#[cfg(test)]
+pub(crate) fn http_request(port: u16, request: &str) -> Result<String, Error> {
+	let mut stream = std::net::TcpStream::connect(("127.0.0.1", port))?;
+	stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+	stream.write_all(request.as_bytes())?;
+	let mut response = vec![];
+	let mut buf = [0u8; 1024];
+	// the response ends when the connection is closed or with the last chunk
+	while !response.ends_with(b"0\r\n\r\n") {
+		let len = stream.read(&mut buf)?;
+		if len == 0 {
+			break;
+		}
+		response.extend_from_slice(&buf[..len]);
+	}
+	Ok(String::from_utf8_lossy(&response).to_string())
+}
+
+// sends a GET request to the container on the specified port and returns the response.
+#[cfg(test)]
+pub(crate) fn http_get(port: u16, uri: &str) -> Result<String, Error> {
+	http_request(
+		port,
+		&format!(
+			"GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+			uri
+		),
+	)
+}
+
+#[test]
+fn test_containers_in_parallel() -> Result<(), Error> {
+	let threads: Vec<_> = (0..4u16)
+		.map(|i| {
+			std::thread::spawn(move || -> Result<(), Error> {
+				let port = 18_700 + i;
+				let root_dir = format!(
+					"{}/rustlet_parallel_{}_{}",
+					std::env::temp_dir().display(),
+					std::process::id(),
+					i
+				);
+				let container = RustletContainer::builder()
+					.config(RustletConfig {
+						http_config: HttpConfig {
+							host: "127.0.0.1".to_string(),
+							port,
+							root_dir: root_dir.clone(),
+							..HttpConfig::default()
+						},
+						..RustletConfig::default()
+					})
+					.rustlet("name", move |_request, response| {
+						response.write(format!("container {}", i).as_bytes())
+					})
+					.mapping("/name", "name")
+					.build()?;
+
+				// each container has its own housekeeper
+				let slots = {
+					let slots = nioruntime_util::lockr!(HOUSEKEEPER_SLOTS);
+					slots
+						.iter()
+						.filter(|slot| match slot {
+							Some(state) => Arc::ptr_eq(state, &container.state),
+							None => false,
+						})
+						.count()
+				};
+				let response = http_get(port, "/name");
+				container.stop(Duration::from_secs(5))?;
+				let _ = std::fs::remove_dir_all(&root_dir);
+
+				assert_eq!(slots, 1);
+				assert!(response?.contains(&format!("container {}", i)));
+				Ok(())
+			})
+		})
+		.collect();
+
+	for thread in threads {
+		thread.join().unwrap()?;
+	}
+	Ok(())
+}
+
+#[test]
+fn test_session_file() -> Result<(), Error> {
+	let mut session = SessionData::new();
+	session.data.insert("user".to_string(), vec![1, 2, 3]);
+	let mut sessions = HashMap::new();
+	sessions.insert(7u128, session);
+	let mut file = vec![];
+	write_sessions(&sessions, &mut file)?;
+
+	let loaded = read_sessions(&mut file.as_slice())?;
+	assert_eq!(loaded.len(), 1);
+	assert_eq!(loaded[&7].data.get("user"), Some(&vec![1, 2, 3]));
+
+	// empty and truncated files, other versions and other files are rejected
+	for len in 0..file.len() {
+		assert!(read_sessions(&mut &file[..len]).is_err());
+	}
+	let mut other_version = file.clone();
+	other_version[7] = 2;
+	assert!(read_sessions(&mut other_version.as_slice()).is_err());
+	assert!(read_sessions(&mut &b"not a session file"[..]).is_err());
+
+	// and ignored when the container starts
+	let path = format!(
+		"{}/rustlet_sessions_{}.dat",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	std::fs::write(&path, &file[..file.len() - 1])?;
+	let state = ContainerState::new();
+	let res = load_sessions(&state, &path);
+	let ignored = {
+		let session_map = nioruntime_util::lockr!(state.session_map);
+		session_map.is_empty()
+	};
+	std::fs::write(&path, &file)?;
+	let res_complete = load_sessions(&state, &path);
+	let _ = std::fs::remove_file(&path);
+	res?;
+	res_complete?;
+	assert!(ignored);
+	let session_map = nioruntime_util::lockr!(state.session_map);
+	assert!(session_map.contains_key(&7));
+	Ok(())
+}
+
+#[test]
+fn test_filter_patterns() {
+	assert!(filter_matches("/admin", "/admin"));
+	assert!(filter_matches("/admin", "/admin/users"));
+	assert!(!filter_matches("/admin", "/administrator"));
+	assert!(!filter_matches("/admin", "/"));
+	assert!(filter_matches("/admin/", "/admin/users"));
+	assert!(!filter_matches("/admin/", "/admin"));
+	assert!(filter_matches("/", "/anything"));
+	assert!(filter_matches("/admin/*", "/admin/users"));
+	assert!(!filter_matches("/admin/*", "/administrator"));
+	assert!(filter_matches("*.rsp", "/pages/index.rsp"));
+	assert!(!filter_matches("*.rsp", "/pages/index.rsp.bak"));
+	assert!(filter_matches("/v?/items", "/v2/items"));
+	assert!(!filter_matches("/v?/items", "/v10/items"));
+
+	assert!(glob_matches(b"*", b""));
+	assert!(glob_matches(b"a*b*c", b"abxxbc"));
+	assert!(glob_matches(b"a*b*c", b"abc"));
+	assert!(!glob_matches(b"a*b*c", b"abcd"));
+	assert!(glob_matches(b"**a", b"bba"));
+	assert!(!glob_matches(b"?", b""));
+	assert!(!glob_matches(b"", b"a"));
+}
+
+// describes the parsed nodes of an RSP, with tags in brackets and attributes sorted by name.
+#[cfg(test)]
+fn describe_rsp(nodes: &Vec<RspNode>) -> String {
+	let tag = |name: &str, tag_attrs: &HashMap<String, String>| {
+		let mut tag_attrs: Vec<String> = tag_attrs
+			.iter()
+			.map(|(key, value)| format!(" {}={}", key, value))
+			.collect();
+		tag_attrs.sort();
+		format!("{}{}", name, tag_attrs.join(""))
+	};
+	nodes
+		.iter()
+		.map(|node| match node {
+			RspNode::Static(data) => String::from_utf8_lossy(data).to_string(),
+			RspNode::Rustlet(name, tag_attrs) => format!("[={}]", tag(name, tag_attrs)),
+			RspNode::Expression(expr, escape) => match escape {
+				true => format!("[:{}]", expr),
+				false => format!("[!{}]", expr),
+			},
+			RspNode::If(name, tag_attrs, if_nodes, else_nodes) => format!(
+				"[if {}]{}[else]{}[end]",
+				tag(name, tag_attrs),
+				describe_rsp(if_nodes),
+				describe_rsp(else_nodes)
+			),
+			RspNode::For(name, tag_attrs, for_nodes) => format!(
+				"[for {}]{}[end]",
+				tag(name, tag_attrs),
+				describe_rsp(for_nodes)
+			),
+			RspNode::Page(tag_attrs) => format!("[{}]", tag("page", tag_attrs)),
+			RspNode::Csrf => "[csrf]".to_string(),
+		})
+		.collect()
+}
+
+#[test]
+fn test_parse_rsp() -> Result<(), Error> {
+	let nodes =
+		parse_rsp(b"<p><@=widget title='a > b' limit=5></p><@: query.name ><@!attr.html><@foo>")?;
+	assert_eq!(
+		describe_rsp(&nodes),
+		"<p>[=widget limit=5 title=a > b]</p>[:query.name][!attr.html]<@foo>"
+	);
+
+	let nodes = parse_rsp(
+		b"<@page status=\"201\">\n<@if rustlet=flag on=yes>a<@for rustlet=items>b<@end>\
+		<@else>c<@end><@csrf>",
+	)?;
+	assert_eq!(
+		describe_rsp(&nodes),
+		"[page status=201]\n[if flag on=yes]a[for items]b[end][else]c[end][csrf]"
+	);
+
+	let invalid: &[&[u8]] = &[
+		b"<@=widget",
+		b"<@=widget title=\"a>",
+		b"<@= >",
+		b"<@=widget title>",
+		b"<@=widget a b=c>",
+		b"<@if on=yes>a<@end>",
+		b"<@end>",
+		b"<@else>",
+		b"<@for rustlet=items>a<@else>b<@end>",
+		b"<@if rustlet=flag>a<@else>b<@else>c<@end>",
+		b"<@if rustlet=flag><@page status=201><@end>",
+		b"<@if rustlet=flag>a",
+	];
+	for rsp in invalid {
+		assert!(parse_rsp(rsp).is_err(), "{}", String::from_utf8_lossy(rsp));
+	}
+
+	assert_eq!(
+		html_escape("<a href=\"x\">'&'</a>"),
+		"&lt;a href=&quot;x&quot;&gt;&#x27;&amp;&#x27;&lt;/a&gt;"
+	);
+	assert_eq!(rsp_content_type("/data.json.rsp"), "application/json");
+	assert_eq!(rsp_content_type("/style.CSS.rsp"), "text/css");
+	assert_eq!(rsp_content_type("/INDEX.RSP"), "text/html");
+	assert_eq!(rsp_content_type("/a.b/page.rsp"), "text/html");
+	Ok(())
+}
+
+#[test]
+fn test_render_rsp() -> Result<(), Error> {
+	let port = 18_710;
+	let root_dir = format!(
+		"{}/rustlet_rsp_{}",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	let container = RustletContainer::builder()
+		.config(RustletConfig {
+			http_config: HttpConfig {
+				host: "127.0.0.1".to_string(),
+				port,
+				root_dir: root_dir.clone(),
+				..HttpConfig::default()
+			},
+			..RustletConfig::default()
+		})
+		.rustlet("widget", |request, response| {
+			let title = request.get_tag_attr("title")?.unwrap_or("".to_string());
+			let limit = request.get_tag_attr("limit")?.unwrap_or("".to_string());
+			response.write(format!("title={},limit={}", title, limit).as_bytes())
+		})
+		.rustlet("flag", |request, _response| {
+			let on = request.get_tag_attr("on")?;
+			request.set_rsp_condition(on == Some("yes".to_string()))
+		})
+		.rustlet("items", |request, _response| {
+			request.set_rsp_loop_items(vec!["a".to_string(), "<b>".to_string()])
+		})
+		.build()?;
+
+	let rsp = "<@page content_type=\"text/plain\">\
+		<@=widget title='a > b' limit=5>|<@:query.name>\
+		|<@if rustlet=flag on=yes>on<@else>off<@end>\
+		|<@if rustlet=flag on=no>on<@else>off<@end>\
+		|<@for rustlet=items><@:loop.index>=<@:loop.value>,<@end>\
+		|<@for rustlet=items><@!loop.value><@end>|";
+	let response = std::fs::write(format!("{}/www/page.rsp", root_dir), rsp)
+		.map_err(|e| Error::from(e))
+		.and_then(|_| http_get(port, "/page.rsp?name=alice"));
+	container.stop(Duration::from_secs(5))?;
+	let _ = std::fs::remove_dir_all(&root_dir);
+
+	let response = response?;
+	assert!(response.contains("text/plain"));
+	assert!(response.contains("title=a > b,limit=5|alice|on|off|0=a,1=&lt;b&gt;,|a<b>|"));
+	Ok(())
 }
@@ -0,0 +1,481 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Serialization of session values. Values stored in the session via [`crate::session`]
+//! implement [`crate::Readable`] and [`crate::Writeable`], which may be derived for structs
+//! and enums with `#[derive(Readable, Writeable)]`. The fields of derived types must
+//! implement [`ReadField`] and [`WriteField`], which are implemented for the integer types,
+//! bool, char, f32, f64, String and for Vec, Option, Box and HashMap of such types. The
+//! derive macros also implement them for the derived type, so derived types may be nested.
+//! The derived code refers to the `librustlet` crate. If it is renamed or re-exported by
+//! another crate, the path to it is specified with `#[rustlet(crate = "path")]`.
+//!
+//! Fields are written in the order that they are declared, and enum variants are written as
+//! their index, so changing the order of fields or variants changes the format. Sessions
+//! that are persisted to a session file are read with the new format after a restart. The
+//! session file itself records the version of its format, and a file written in another
+//! version, or one that is truncated or corrupt, is ignored with a warning when the container
+//! starts.
+//!
+//! Types that implement serde's `Serialize` and `Deserialize` may be stored by wrapping them
+//! in [`Serde`], which stores them as JSON.
+//!
+//! # Examples
+//! ```no_run
+//! use nioruntime_err::Error;
+//! use librustlet::*;
+//! use nioruntime_log::*;
+//!
+//! debug!();
+//!
+//! #[derive(Readable, Writeable, Debug)]
+//! enum Role {
+//!     Admin,
+//!     User { groups: Vec<String> },
+//! }
+//!
+//! #[derive(Readable, Writeable, Debug)]
+//! struct Account {
+//!     id: u64,
+//!     name: String,
+//!     email: Option<String>,
+//!     role: Role,
+//! }
+//!
+//! fn main() -> Result<(), Error> {
+//!     rustlet_init!(RustletConfig::default());
+//!
+//!     rustlet!("login", {
+//!         let account = Account {
+//!             id: 1,
+//!             name: "alice".to_string(),
+//!             email: None,
+//!             role: Role::User {
+//!                 groups: vec!["staff".to_string()],
+//!             },
+//!         };
+//!         session!("account", account);
+//!     });
+//!
+//!     rustlet!("account", {
+//!         let account: Option<Account> = session!("account");
+//!         response!("account={:?}", account);
+//!     });
+//!
+//!     rustlet_mapping!("/login", "login");
+//!     rustlet_mapping!("/account", "account");
+//!
+//!     Ok(())
+//! }
+//! ```
+
+use crate::{Readable, Reader, Writeable, Writer};
+use nioruntime_err::{Error, ErrorKind};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::ops::Deref;
+
+/// A type that can be read as a field of a type that derives [`crate::Readable`].
+pub trait ReadField: Sized {
+	/// Read the field from the reader.
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error>;
+}
+
+/// A type that can be written as a field of a type that derives [`crate::Writeable`].
+pub trait WriteField {
+	/// Write the field to the writer.
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error>;
+}
+
+macro_rules! impl_field {
+	($type:ty, $read:ident, $write:ident) => {
+		impl ReadField for $type {
+			fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+				reader.$read()
+			}
+		}
+
+		impl WriteField for $type {
+			fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+				writer.$write(*self)
+			}
+		}
+	};
+	// types that are written as another type of the same size
+	($type:ty, $as:ty, $read:ident, $write:ident) => {
+		impl ReadField for $type {
+			fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+				Ok(reader.$read()? as $type)
+			}
+		}
+
+		impl WriteField for $type {
+			fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+				writer.$write(*self as $as)
+			}
+		}
+	};
+}
+
+impl_field!(u8, read_u8, write_u8);
+impl_field!(u16, read_u16, write_u16);
+impl_field!(u32, read_u32, write_u32);
+impl_field!(u64, read_u64, write_u64);
+impl_field!(i32, read_i32, write_i32);
+impl_field!(i64, read_i64, write_i64);
+impl_field!(i8, u8, read_u8, write_u8);
+impl_field!(i16, u16, read_u16, write_u16);
+impl_field!(usize, u64, read_u64, write_u64);
+impl_field!(isize, i64, read_i64, write_i64);
+
+impl ReadField for u128 {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let high = reader.read_u64()? as u128;
+		let low = reader.read_u64()? as u128;
+		Ok((high << 64) | low)
+	}
+}
+
+impl WriteField for u128 {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u64((*self >> 64) as u64)?;
+		writer.write_u64(*self as u64)
+	}
+}
+
+impl ReadField for i128 {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		Ok(u128::read_field(reader)? as i128)
+	}
+}
+
+impl WriteField for i128 {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		(*self as u128).write_field(writer)
+	}
+}
+
+impl ReadField for bool {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		match reader.read_u8()? {
+			0 => Ok(false),
+			1 => Ok(true),
+			b => Err(ErrorKind::CorruptedData(format!("invalid bool: {}", b)).into()),
+		}
+	}
+}
+
+impl WriteField for bool {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u8(*self as u8)
+	}
+}
+
+impl ReadField for char {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let c = reader.read_u32()?;
+		match std::char::from_u32(c) {
+			Some(c) => Ok(c),
+			None => Err(ErrorKind::CorruptedData(format!("invalid char: {}", c)).into()),
+		}
+	}
+}
+
+impl WriteField for char {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u32(*self as u32)
+	}
+}
+
+impl ReadField for f32 {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		Ok(f32::from_bits(reader.read_u32()?))
+	}
+}
+
+impl WriteField for f32 {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u32(self.to_bits())
+	}
+}
+
+impl ReadField for f64 {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		Ok(f64::from_bits(reader.read_u64()?))
+	}
+}
+
+impl WriteField for f64 {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u64(self.to_bits())
+	}
+}
+
+impl ReadField for String {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let bytes = reader.read_bytes_len_prefix()?;
+		Ok(std::str::from_utf8(&bytes)?.to_string())
+	}
+}
+
+impl WriteField for String {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_bytes(self.as_bytes())
+	}
+}
+
+impl<T: ReadField> ReadField for Vec<T> {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let len = reader.read_u64()?;
+		// the length is not trusted, so the vec grows as the elements are read
+		let mut vec = vec![];
+		for _ in 0..len {
+			vec.push(T::read_field(reader)?);
+		}
+		Ok(vec)
+	}
+}
+
+impl<T: WriteField> WriteField for Vec<T> {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u64(self.len() as u64)?;
+		for value in self {
+			value.write_field(writer)?;
+		}
+		Ok(())
+	}
+}
+
+impl<T: ReadField> ReadField for Option<T> {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		match reader.read_u8()? {
+			0 => Ok(None),
+			1 => Ok(Some(T::read_field(reader)?)),
+			b => Err(ErrorKind::CorruptedData(format!("invalid option: {}", b)).into()),
+		}
+	}
+}
+
+impl<T: WriteField> WriteField for Option<T> {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		match self {
+			Some(value) => {
+				writer.write_u8(1)?;
+				value.write_field(writer)
+			}
+			None => writer.write_u8(0),
+		}
+	}
+}
+
+impl<T: ReadField> ReadField for Box<T> {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		Ok(Box::new(T::read_field(reader)?))
+	}
+}
+
+impl<T: WriteField> WriteField for Box<T> {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		(**self).write_field(writer)
+	}
+}
+
+impl<K: ReadField + Eq + Hash, V: ReadField> ReadField for HashMap<K, V> {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let len = reader.read_u64()?;
+		let mut map = HashMap::new();
+		for _ in 0..len {
+			let key = K::read_field(reader)?;
+			let value = V::read_field(reader)?;
+			map.insert(key, value);
+		}
+		Ok(map)
+	}
+}
+
+impl<K: WriteField, V: WriteField> WriteField for HashMap<K, V> {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		writer.write_u64(self.len() as u64)?;
+		for (key, value) in self {
+			key.write_field(writer)?;
+			value.write_field(writer)?;
+		}
+		Ok(())
+	}
+}
+
+/// A value that is serialized with serde. The value is stored as JSON, so that types that
+/// implement serde's `Serialize` and `Deserialize` may be stored in the session or used as a
+/// field of a type that derives [`crate::Readable`] and [`crate::Writeable`].
+///
+/// # Examples
+/// ```no_run
+/// use nioruntime_err::Error;
+/// use librustlet::*;
+/// use librustlet::ser::Serde;
+/// use nioruntime_log::*;
+/// use serde::{Deserialize, Serialize};
+///
+/// debug!();
+///
+/// #[derive(Serialize, Deserialize, Debug)]
+/// struct Cart {
+///     items: Vec<String>,
+/// }
+///
+/// fn main() -> Result<(), Error> {
+///     rustlet_init!(RustletConfig::default());
+///
+///     rustlet!("cart", {
+///         let cart: Option<Serde<Cart>> = session!("cart");
+///         let mut cart = cart.map(|cart| cart.0).unwrap_or(Cart { items: vec![] });
+///         cart.items.push(query!("item"));
+///         response!("cart={:?}", cart);
+///         session!("cart", Serde(cart));
+///     });
+///
+///     rustlet_mapping!("/cart", "cart");
+///
+///     Ok(())
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct Serde<T>(pub T);
+
+impl<T> Deref for Serde<T> {
+	type Target = T;
+	fn deref(&self) -> &T {
+		&self.0
+	}
+}
+
+impl<T: DeserializeOwned> ReadField for Serde<T> {
+	fn read_field<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		let bytes = reader.read_bytes_len_prefix()?;
+		match serde_json::from_slice(&bytes) {
+			Ok(value) => Ok(Serde(value)),
+			Err(e) => Err(ErrorKind::CorruptedData(format!("invalid JSON: {}", e)).into()),
+		}
+	}
+}
+
+impl<T: Serialize> WriteField for Serde<T> {
+	fn write_field<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		match serde_json::to_vec(&self.0) {
+			Ok(bytes) => writer.write_bytes(bytes),
+			Err(e) => Err(ErrorKind::InternalError(format!("couldn't serialize: {}", e)).into()),
+		}
+	}
+}
+
+impl<T: DeserializeOwned> Readable for Serde<T> {
+	fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
+		Self::read_field(reader)
+	}
+}
+
+impl<T: Serialize> Writeable for Serde<T> {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
+		self.write_field(writer)
+	}
+}
+
+#[cfg(test)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+struct TestPreferences {
+	theme: String,
+	page_size: u32,
+}
+
+#[cfg(test)]
+#[derive(Readable, Writeable, Debug, Clone, PartialEq)]
+#[rustlet(crate = "crate")]
+enum TestRole {
+	Guest,
+	User { since: u64, groups: Vec<String> },
+	Admin(Option<Box<TestRole>>, char),
+}
+
+#[cfg(test)]
+#[derive(Readable, Writeable, Debug, Clone, PartialEq)]
+#[rustlet(crate = "crate")]
+struct TestPair<T>(T, Option<T>);
+
+#[cfg(test)]
+#[derive(Readable, Writeable, Debug, Clone, PartialEq)]
+#[rustlet(crate = "crate")]
+struct TestAccount {
+	id: u64,
+	name: String,
+	email: Option<String>,
+	roles: Vec<TestRole>,
+	limits: HashMap<String, i32>,
+	balance: f64,
+	active: bool,
+	pair: TestPair<i16>,
+	preferences: Serde<TestPreferences>,
+}
+
+#[test]
+fn test_derive_round_trip() -> Result<(), Error> {
+	let mut limits = HashMap::new();
+	limits.insert("requests".to_string(), 100);
+	limits.insert("uploads".to_string(), -1);
+	let account = TestAccount {
+		id: 1,
+		name: "alice".to_string(),
+		email: Some("alice@example.com".to_string()),
+		roles: vec![
+			TestRole::Guest,
+			TestRole::User {
+				since: 1234,
+				groups: vec!["staff".to_string(), "ops".to_string()],
+			},
+			TestRole::Admin(Some(Box::new(TestRole::Guest)), 'é'),
+			TestRole::Admin(None, 'a'),
+		],
+		limits,
+		balance: -12.5,
+		active: true,
+		pair: TestPair(-3, None),
+		preferences: Serde(TestPreferences {
+			theme: "dark".to_string(),
+			page_size: 50,
+		}),
+	};
+
+	let mut bytes = vec![];
+	nioruntime_util::ser::serialize(&mut bytes, &account)?;
+	let read: TestAccount = nioruntime_util::ser::deserialize(&mut &bytes[..])?;
+	assert_eq!(read, account);
+
+	// every truncation of the data is an error rather than a partial value
+	for len in 0..bytes.len() {
+		let read: Result<TestAccount, Error> =
+			nioruntime_util::ser::deserialize(&mut &bytes[..len]);
+		assert!(read.is_err(), "truncated to {} bytes", len);
+	}
+
+	// an unknown variant index is an error
+	let mut bytes = vec![];
+	nioruntime_util::ser::serialize(&mut bytes, &TestRole::Guest)?;
+	let last = bytes.len() - 1;
+	bytes[last] = 3;
+	let read: Result<TestRole, Error> = nioruntime_util::ser::deserialize(&mut &bytes[..]);
+	assert!(read.is_err());
+
+	Ok(())
+}
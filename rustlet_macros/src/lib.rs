@@ -0,0 +1,434 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Procedural macros for librustlet. These macros are re-exported by librustlet and should be
+//! used from there.
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::{format_ident, quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{
+	parse_macro_input, parse_quote, Attribute, AttributeArgs, Data, DeriveInput, Fields, FnArg,
+	Generics, ItemFn, Lit, Meta, NestedMeta, Type,
+};
+
+/// Declares a rustlet and its mapping. See `librustlet::declaration`.
+#[proc_macro_attribute]
+pub fn rustlet(attr: TokenStream, item: TokenStream) -> TokenStream {
+	let args = parse_macro_input!(attr as AttributeArgs);
+	let function = parse_macro_input!(item as ItemFn);
+	match expand_rustlet(args, function) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+fn expand_rustlet(args: AttributeArgs, function: ItemFn) -> syn::Result<proc_macro2::TokenStream> {
+	let mut path = None;
+	let mut method = None;
+	for arg in &args {
+		let (key, value) = match arg {
+			NestedMeta::Meta(Meta::NameValue(nv)) => match &nv.lit {
+				Lit::Str(value) => (nv.path.clone(), value.clone()),
+				_ => return Err(syn::Error::new(nv.lit.span(), "expected a string")),
+			},
+			_ => {
+				return Err(syn::Error::new(
+					arg.span(),
+					"expected path = \"...\" or method = \"...\"",
+				))
+			}
+		};
+		if key.is_ident("path") {
+			if !value.value().starts_with('/') {
+				return Err(syn::Error::new(
+					value.span(),
+					"the path must start with '/'",
+				));
+			}
+			path = Some(value);
+		} else if key.is_ident("method") {
+			method = Some(match value.value().to_uppercase().as_str() {
+				"GET" => quote! { Some(librustlet::nioruntime_http::HttpMethod::Get) },
+				"POST" => quote! { Some(librustlet::nioruntime_http::HttpMethod::Post) },
+				_ => {
+					return Err(syn::Error::new(
+						value.span(),
+						"the method must be \"GET\" or \"POST\"",
+					))
+				}
+			});
+		} else {
+			return Err(syn::Error::new(
+				key.span(),
+				"unknown parameter, expected path or method",
+			));
+		}
+	}
+
+	let path = match path {
+		Some(path) => path,
+		None => {
+			return Err(syn::Error::new(
+				proc_macro2::Span::call_site(),
+				"the path parameter is required: #[rustlet(path = \"/...\")]",
+			))
+		}
+	};
+	let method = method.unwrap_or(quote! { None });
+
+	let ident = &function.sig.ident;
+	let name = ident.to_string();
+	// the symbol is named after the path, so that the compiler (or the linker, if the rustlets
+	// are declared in different crates) rejects paths that are declared more than once.
+	let path_symbol = format!("__rustlet_path_{}", symbol_name(&path.value()));
+
+	// the request and response are passed through, the other parameters are extracted
+	let mut extractions = vec![];
+	let mut args = vec![];
+	for (i, input) in function.sig.inputs.iter().enumerate() {
+		let ty = match input {
+			FnArg::Typed(pat_type) => &pat_type.ty,
+			FnArg::Receiver(receiver) => {
+				return Err(syn::Error::new(
+					receiver.span(),
+					"a rustlet can't take self",
+				))
+			}
+		};
+		match passed_through(ty) {
+			Some(arg) => args.push(arg),
+			None => {
+				let arg = format_ident!("__arg{}", i);
+				extractions.push(quote_spanned! {ty.span()=>
+					let #arg: #ty = match librustlet::extract::extract_or_reject::<#ty>(
+						request,
+						response,
+					)? {
+						Some(value) => value,
+						None => return Ok(()),
+					};
+				});
+				args.push(quote! { #arg });
+			}
+		}
+	}
+
+	Ok(quote! {
+		#function
+
+		const _: () = {
+			#[doc(hidden)]
+			#[export_name = #path_symbol]
+			pub static __RUSTLET_PATH: u8 = 0;
+		};
+
+		librustlet::inventory::submit! {
+			#![crate = librustlet]
+			librustlet::declaration::RustletDeclaration {
+				name: concat!(module_path!(), "::", #name),
+				path: #path,
+				method: #method,
+				handler: {
+					fn __rustlet_handler(
+						request: &mut librustlet::RustletRequest,
+						response: &mut librustlet::RustletResponse,
+					) -> Result<(), librustlet::Error> {
+						#(#extractions)*
+						#ident(#(#args),*)
+					}
+					__rustlet_handler
+				},
+			}
+		}
+	})
+}
+
+// returns the path with the characters that are not allowed in symbol names replaced by '_'
+// and their hex value.
+fn symbol_name(path: &str) -> String {
+	path.bytes()
+		.map(|b| match b.is_ascii_alphanumeric() {
+			true => (b as char).to_string(),
+			false => format!("_{:02x}", b),
+		})
+		.collect()
+}
+
+// returns the argument to pass for the request or the response, or None if the parameter
+// is extracted.
+fn passed_through(ty: &Type) -> Option<proc_macro2::TokenStream> {
+	let elem = match ty {
+		Type::Reference(reference) if reference.mutability.is_some() => &reference.elem,
+		_ => return None,
+	};
+	let ident = match &**elem {
+		Type::Path(path) => &path.path.segments.last()?.ident,
+		_ => return None,
+	};
+	if ident == "RustletRequest" {
+		Some(quote! { request })
+	} else if ident == "RustletResponse" {
+		Some(quote! { response })
+	} else {
+		None
+	}
+}
+
+/// Derives `librustlet::Readable` and `librustlet::ser::ReadField`. See `librustlet::ser`.
+/// If librustlet is renamed or re-exported, the path to it is specified with
+/// `#[rustlet(crate = "path")]`.
+#[proc_macro_derive(Readable, attributes(rustlet))]
+pub fn derive_readable(item: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(item as DeriveInput);
+	match expand_readable(input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+/// Derives `librustlet::Writeable` and `librustlet::ser::WriteField`. See `librustlet::ser`.
+/// If librustlet is renamed or re-exported, the path to it is specified with
+/// `#[rustlet(crate = "path")]`.
+#[proc_macro_derive(Writeable, attributes(rustlet))]
+pub fn derive_writeable(item: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(item as DeriveInput);
+	match expand_writeable(input) {
+		Ok(tokens) => tokens.into(),
+		Err(e) => e.to_compile_error().into(),
+	}
+}
+
+// returns the path to librustlet, from the #[rustlet(crate = "path")] attribute if present.
+fn crate_path(attrs: &[Attribute]) -> syn::Result<syn::Path> {
+	let mut path = parse_quote!(librustlet);
+	for attr in attrs.iter().filter(|attr| attr.path.is_ident("rustlet")) {
+		let list = match attr.parse_meta()? {
+			Meta::List(list) => list,
+			meta => {
+				return Err(syn::Error::new(
+					meta.span(),
+					"expected #[rustlet(crate = \"...\")]",
+				))
+			}
+		};
+		for nested in &list.nested {
+			match nested {
+				NestedMeta::Meta(Meta::NameValue(nv)) if nv.path.is_ident("crate") => {
+					match &nv.lit {
+						Lit::Str(value) => path = value.parse()?,
+						_ => return Err(syn::Error::new(nv.lit.span(), "expected a string")),
+					}
+				}
+				_ => {
+					return Err(syn::Error::new(
+						nested.span(),
+						"unknown parameter, expected crate = \"...\"",
+					))
+				}
+			}
+		}
+	}
+	Ok(path)
+}
+
+fn expand_readable(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let krate = crate_path(&input.attrs)?;
+	let ident = &input.ident;
+	let name = ident.to_string();
+	let generics = add_bounds(input.generics.clone(), quote! { #krate::ser::ReadField });
+	let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+	let body = match &input.data {
+		Data::Struct(data) => {
+			let value = construct(&krate, quote! { #ident }, &data.fields);
+			quote! { ::std::result::Result::Ok(#value) }
+		}
+		Data::Enum(data) => {
+			let arms = data.variants.iter().enumerate().map(|(i, variant)| {
+				let index = i as u32;
+				let variant_ident = &variant.ident;
+				let value = construct(&krate, quote! { #ident::#variant_ident }, &variant.fields);
+				quote! { #index => ::std::result::Result::Ok(#value), }
+			});
+			quote! {
+				match #krate::Reader::read_u32(reader)? {
+					#(#arms)*
+					index => ::std::result::Result::Err(
+						#krate::ErrorKind::CorruptedData(::std::format!(
+							"invalid variant {} of {}",
+							index,
+							#name
+						))
+						.into(),
+					),
+				}
+			}
+		}
+		Data::Union(_) => {
+			return Err(syn::Error::new_spanned(
+				ident,
+				"Readable can't be derived for unions",
+			))
+		}
+	};
+
+	Ok(quote! {
+		impl #impl_generics #krate::Readable for #ident #ty_generics #where_clause {
+			fn read<__R: #krate::Reader>(
+				reader: &mut __R,
+			) -> ::std::result::Result<Self, #krate::Error> {
+				#body
+			}
+		}
+
+		impl #impl_generics #krate::ser::ReadField for #ident #ty_generics #where_clause {
+			fn read_field<__R: #krate::Reader>(
+				reader: &mut __R,
+			) -> ::std::result::Result<Self, #krate::Error> {
+				<Self as #krate::Readable>::read(reader)
+			}
+		}
+	})
+}
+
+fn expand_writeable(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+	let krate = crate_path(&input.attrs)?;
+	let ident = &input.ident;
+	let generics = add_bounds(input.generics.clone(), quote! { #krate::ser::WriteField });
+	let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+	let body = match &input.data {
+		Data::Struct(data) => {
+			let (pattern, writes) = destructure(&krate, quote! { #ident }, &data.fields);
+			quote! {
+				let #pattern = self;
+				#(#writes)*
+				::std::result::Result::Ok(())
+			}
+		}
+		// an enum without variants has no values
+		Data::Enum(data) if data.variants.is_empty() => quote! { match *self {} },
+		Data::Enum(data) => {
+			let arms = data.variants.iter().enumerate().map(|(i, variant)| {
+				let index = i as u32;
+				let variant_ident = &variant.ident;
+				let (pattern, writes) =
+					destructure(&krate, quote! { #ident::#variant_ident }, &variant.fields);
+				quote! {
+					#pattern => {
+						#krate::Writer::write_u32(writer, #index)?;
+						#(#writes)*
+					}
+				}
+			});
+			quote! {
+				match self {
+					#(#arms)*
+				}
+				::std::result::Result::Ok(())
+			}
+		}
+		Data::Union(_) => {
+			return Err(syn::Error::new_spanned(
+				ident,
+				"Writeable can't be derived for unions",
+			))
+		}
+	};
+
+	Ok(quote! {
+		impl #impl_generics #krate::Writeable for #ident #ty_generics #where_clause {
+			fn write<__W: #krate::Writer>(
+				&self,
+				writer: &mut __W,
+			) -> ::std::result::Result<(), #krate::Error> {
+				#body
+			}
+		}
+
+		impl #impl_generics #krate::ser::WriteField for #ident #ty_generics #where_clause {
+			fn write_field<__W: #krate::Writer>(
+				&self,
+				writer: &mut __W,
+			) -> ::std::result::Result<(), #krate::Error> {
+				<Self as #krate::Writeable>::write(self, writer)
+			}
+		}
+	})
+}
+
+// adds the specified bound to the type parameters.
+fn add_bounds(mut generics: Generics, bound: proc_macro2::TokenStream) -> Generics {
+	for param in generics.type_params_mut() {
+		param.bounds.push(parse_quote!(#bound));
+	}
+	generics
+}
+
+// returns the expression that reads the fields, in order, and constructs the value.
+fn construct(
+	krate: &syn::Path,
+	path: proc_macro2::TokenStream,
+	fields: &Fields,
+) -> proc_macro2::TokenStream {
+	let reads = fields.iter().map(|field| {
+		let ty = &field.ty;
+		let read = quote_spanned! {ty.span()=>
+			<#ty as #krate::ser::ReadField>::read_field(reader)?
+		};
+		match &field.ident {
+			Some(ident) => quote! { #ident: #read },
+			None => read,
+		}
+	});
+	match fields {
+		Fields::Named(_) => quote! { #path { #(#reads),* } },
+		Fields::Unnamed(_) => quote! { #path(#(#reads),*) },
+		Fields::Unit => path,
+	}
+}
+
+// returns the pattern that binds the fields of a value and the statements that write them,
+// in order.
+fn destructure(
+	krate: &syn::Path,
+	path: proc_macro2::TokenStream,
+	fields: &Fields,
+) -> (proc_macro2::TokenStream, Vec<proc_macro2::TokenStream>) {
+	let bindings: Vec<proc_macro2::Ident> = (0..fields.len())
+		.map(|i| format_ident!("__field{}", i))
+		.collect();
+	let writes = fields
+		.iter()
+		.zip(&bindings)
+		.map(|(field, binding)| {
+			let ty = &field.ty;
+			quote_spanned! {ty.span()=>
+				#krate::ser::WriteField::write_field(#binding, writer)?;
+			}
+		})
+		.collect();
+	let pattern = match fields {
+		Fields::Named(_) => {
+			let idents = fields.iter().map(|field| &field.ident);
+			quote! { #path { #(#idents: #bindings),* } }
+		}
+		Fields::Unnamed(_) => quote! { #path(#(#bindings),*) },
+		Fields::Unit => path,
+	};
+	(pattern, writes)
+}
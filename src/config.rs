@@ -0,0 +1,599 @@
+// Copyright 2021 The BMW Developers
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The configuration of the rustlet server. The configuration is loaded from a TOML file
+//! whose keys are the names of the fields of [`RustletConfig`], [`HttpConfig`] and
+//! [`EventHandlerConfig`]. Each key may be overridden by an environment variable, whose name
+//! is the key in upper case with '.' replaced by '_' and prefixed with `RUSTLET_` (for
+//! example `RUSTLET_HTTP_CONFIG_PORT`), and by the command line via `--set key=value`.
+
+use clap::ArgMatches;
+use librustlet::*;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{metadata, File};
+use std::io::Read;
+use toml::value::Table;
+use toml::Value;
+
+const ENV_PREFIX: &str = "RUSTLET_";
+
+// the keys that may be overridden by environment variables.
+const KEYS: &[&str] = &[
+	"session_timeout",
+	"session_file",
+	"plugins",
+	"http_config.host",
+	"http_config.port",
+	"http_config.root_dir",
+	"http_config.server_name",
+	"http_config.request_log_params",
+	"http_config.request_log_separator_char",
+	"http_config.request_log_max_size",
+	"http_config.request_log_max_age_millis",
+	"http_config.delete_request_rotation",
+	"http_config.main_log_max_size",
+	"http_config.main_log_max_age_millis",
+	"http_config.stats_log_max_size",
+	"http_config.stats_log_max_age_millis",
+	"http_config.last_request_timeout",
+	"http_config.stats_frequency",
+	"http_config.read_timeout",
+	"http_config.max_log_queue",
+	"http_config.debug",
+	"http_config.evh_config.thread_count",
+	"http_config.evh_config.tls_config.certificates_file",
+	"http_config.evh_config.tls_config.private_key_file",
+];
+
+// the keys whose values are strings. Their values are never parsed as TOML values, so that
+// for example `--set http_config.server_name=1.0` is not turned into a number.
+const STRING_KEYS: &[&str] = &[
+	"session_file",
+	"http_config.host",
+	"http_config.root_dir",
+	"http_config.server_name",
+	"http_config.request_log_separator_char",
+	"http_config.evh_config.tls_config.certificates_file",
+	"http_config.evh_config.tls_config.private_key_file",
+];
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	session_timeout: Option<u64>,
+	session_file: Option<String>,
+	plugins: Option<bool>,
+	http_config: Option<HttpConfigFile>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct HttpConfigFile {
+	host: Option<String>,
+	port: Option<u16>,
+	root_dir: Option<String>,
+	server_name: Option<String>,
+	request_log_params: Option<Vec<String>>,
+	request_log_separator_char: Option<char>,
+	request_log_max_size: Option<u64>,
+	request_log_max_age_millis: Option<u64>,
+	delete_request_rotation: Option<bool>,
+	main_log_max_size: Option<u64>,
+	main_log_max_age_millis: Option<u64>,
+	stats_log_max_size: Option<u64>,
+	stats_log_max_age_millis: Option<u64>,
+	last_request_timeout: Option<u64>,
+	stats_frequency: Option<u64>,
+	read_timeout: Option<u64>,
+	max_log_queue: Option<usize>,
+	debug: Option<bool>,
+	evh_config: Option<EvhConfigFile>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct EvhConfigFile {
+	thread_count: Option<usize>,
+	tls_config: Option<TlsConfigFile>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsConfigFile {
+	certificates_file: Option<String>,
+	private_key_file: Option<String>,
+}
+
+// the configuration that is used if no configuration file is specified.
+fn default_config(version: &str) -> RustletConfig {
+	RustletConfig {
+		session_timeout: 60,
+		http_config: HttpConfig {
+			evh_config: EventHandlerConfig {
+				thread_count: 8,
+				tls_config: None,
+			},
+			max_log_queue: 100_000,
+			stats_frequency: 10_000,
+			server_name: format!("Rustlet Httpd {}", version),
+			..Default::default()
+		},
+		..RustletConfig::default()
+	}
+}
+
+/// Load the configuration from the configuration file, the environment and the command line,
+/// in increasing order of precedence, and validate it.
+pub fn load(args: &ArgMatches, version: &str) -> Result<RustletConfig, Error> {
+	let mut table = match args.value_of("config") {
+		Some(file) => {
+			let mut content = String::new();
+			File::open(file)
+				.map_err(|e| {
+					ErrorKind::SetupError(format!("couldn't open config file '{}': {}", file, e))
+				})?
+				.read_to_string(&mut content)?;
+			match content.parse::<Value>() {
+				Ok(Value::Table(table)) => table,
+				Ok(_) => Table::new(),
+				Err(e) => {
+					return Err(ErrorKind::SetupError(format!(
+						"invalid config file '{}': {}",
+						file, e
+					))
+					.into())
+				}
+			}
+		}
+		None => Table::new(),
+	};
+
+	for key in KEYS {
+		let name = format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase());
+		match std::env::var(&name) {
+			Ok(value) => set(&mut table, key, &value)?,
+			Err(_) => {}
+		}
+	}
+
+	// the flags of the command line
+	if args.is_present("debug") {
+		set(&mut table, "http_config.debug", "true")?;
+	}
+	if args.is_present("delete_request_rotation") {
+		set(&mut table, "http_config.delete_request_rotation", "true")?;
+	}
+	if args.is_present("plugins") {
+		set(&mut table, "plugins", "true")?;
+	}
+	match args.value_of("certs") {
+		Some(certs) => set(
+			&mut table,
+			"http_config.evh_config.tls_config.certificates_file",
+			certs,
+		)?,
+		None => {}
+	}
+	match args.value_of("private_key") {
+		Some(private_key) => set(
+			&mut table,
+			"http_config.evh_config.tls_config.private_key_file",
+			private_key,
+		)?,
+		None => {}
+	}
+	match args.values_of("set") {
+		Some(values) => {
+			for value in values {
+				match value.find('=') {
+					Some(pos) => set(&mut table, &value[..pos], &value[(pos + 1)..])?,
+					None => {
+						return Err(ErrorKind::SetupError(format!(
+							"invalid setting '{}', expected key=value",
+							value
+						))
+						.into())
+					}
+				}
+			}
+		}
+		None => {}
+	}
+
+	let file: ConfigFile = Value::Table(table)
+		.try_into()
+		.map_err(|e| ErrorKind::SetupError(format!("invalid configuration: {}", e)))?;
+	let config = apply(file, default_config(version));
+	validate(&config)?;
+	Ok(config)
+}
+
+// set the value of a dotted key, such as http_config.port. The values of string keys are
+// taken as is. Other values are parsed as TOML values, so that numbers, booleans and arrays
+// may be specified.
+fn set(table: &mut Table, key: &str, value: &str) -> Result<(), Error> {
+	let value = match STRING_KEYS.contains(&key) {
+		true => Value::String(value.to_string()),
+		false => match format!("value = {}", value).parse::<Value>() {
+			Ok(Value::Table(mut parsed)) => match parsed.remove("value") {
+				Some(value) => value,
+				None => Value::String(value.to_string()),
+			},
+			_ => Value::String(value.to_string()),
+		},
+	};
+
+	let mut table = table;
+	let mut names: Vec<&str> = key.split('.').collect();
+	let last = names.pop().unwrap_or("");
+	for name in names {
+		let entry = table
+			.entry(name.to_string())
+			.or_insert(Value::Table(Table::new()));
+		table = match entry {
+			Value::Table(table) => table,
+			_ => {
+				return Err(ErrorKind::SetupError(format!(
+					"invalid setting '{}': '{}' is not a table",
+					key, name
+				))
+				.into())
+			}
+		};
+	}
+	table.insert(last.to_string(), value);
+	Ok(())
+}
+
+// apply the values of the configuration file to the specified configuration.
+fn apply(file: ConfigFile, mut config: RustletConfig) -> RustletConfig {
+	config.session_timeout = file.session_timeout.unwrap_or(config.session_timeout);
+	config.session_file = file.session_file.or(config.session_file);
+	config.plugins = file.plugins.unwrap_or(config.plugins);
+
+	let http = file.http_config.unwrap_or_default();
+	let mut http_config = config.http_config;
+	http_config.host = http.host.unwrap_or(http_config.host);
+	http_config.port = http.port.unwrap_or(http_config.port);
+	http_config.root_dir = http.root_dir.unwrap_or(http_config.root_dir);
+	http_config.server_name = http.server_name.unwrap_or(http_config.server_name);
+	http_config.request_log_params = http
+		.request_log_params
+		.unwrap_or(http_config.request_log_params);
+	http_config.request_log_separator_char = http
+		.request_log_separator_char
+		.unwrap_or(http_config.request_log_separator_char);
+	http_config.request_log_max_size = http
+		.request_log_max_size
+		.unwrap_or(http_config.request_log_max_size);
+	http_config.request_log_max_age_millis = http
+		.request_log_max_age_millis
+		.map(|v| v as u128)
+		.unwrap_or(http_config.request_log_max_age_millis);
+	http_config.delete_request_rotation = http
+		.delete_request_rotation
+		.unwrap_or(http_config.delete_request_rotation);
+	http_config.main_log_max_size = http
+		.main_log_max_size
+		.unwrap_or(http_config.main_log_max_size);
+	http_config.main_log_max_age_millis = http
+		.main_log_max_age_millis
+		.map(|v| v as u128)
+		.unwrap_or(http_config.main_log_max_age_millis);
+	http_config.stats_log_max_size = http
+		.stats_log_max_size
+		.unwrap_or(http_config.stats_log_max_size);
+	http_config.stats_log_max_age_millis = http
+		.stats_log_max_age_millis
+		.map(|v| v as u128)
+		.unwrap_or(http_config.stats_log_max_age_millis);
+	http_config.last_request_timeout = http
+		.last_request_timeout
+		.map(|v| v as u128)
+		.unwrap_or(http_config.last_request_timeout);
+	http_config.stats_frequency = http.stats_frequency.unwrap_or(http_config.stats_frequency);
+	http_config.read_timeout = http
+		.read_timeout
+		.map(|v| v as u128)
+		.unwrap_or(http_config.read_timeout);
+	http_config.max_log_queue = http.max_log_queue.unwrap_or(http_config.max_log_queue);
+	http_config.debug = http.debug.unwrap_or(http_config.debug);
+
+	let evh = http.evh_config.unwrap_or_default();
+	http_config.evh_config.thread_count = evh
+		.thread_count
+		.unwrap_or(http_config.evh_config.thread_count);
+	// a missing file is left empty, which is reported by validate
+	match evh.tls_config {
+		Some(tls) => {
+			http_config.evh_config.tls_config = Some(TlsConfig {
+				certificates_file: tls.certificates_file.unwrap_or_default(),
+				private_key_file: tls.private_key_file.unwrap_or_default(),
+			})
+		}
+		None => {}
+	}
+
+	config.http_config = http_config;
+	config
+}
+
+// validate the values of the configuration.
+fn validate(config: &RustletConfig) -> Result<(), Error> {
+	let http_config = &config.http_config;
+	let mut errors = vec![];
+	if http_config.host.trim().is_empty() {
+		errors.push("http_config.host must not be empty".to_string());
+	}
+	if http_config.port == 0 {
+		errors.push("http_config.port must not be 0".to_string());
+	}
+	if http_config.root_dir.trim().is_empty() {
+		errors.push("http_config.root_dir must not be empty".to_string());
+	}
+	if http_config.evh_config.thread_count == 0 {
+		errors.push("http_config.evh_config.thread_count must be at least 1".to_string());
+	}
+	if http_config.max_log_queue == 0 {
+		errors.push("http_config.max_log_queue must be at least 1".to_string());
+	}
+	if http_config.stats_frequency == 0 {
+		errors.push("http_config.stats_frequency must be at least 1".to_string());
+	}
+	if http_config.read_timeout == 0 {
+		errors.push("http_config.read_timeout must be at least 1".to_string());
+	}
+	if http_config.last_request_timeout == 0 {
+		errors.push("http_config.last_request_timeout must be at least 1".to_string());
+	}
+	match &http_config.evh_config.tls_config {
+		Some(tls_config)
+			if tls_config.certificates_file.is_empty() || tls_config.private_key_file.is_empty() =>
+		{
+			errors.push(
+				"either both http_config.evh_config.tls_config.certificates_file (--certs) and private_key_file (--private_key) or neither must be specified"
+					.to_string(),
+			)
+		}
+		Some(tls_config) => {
+			for (key, file) in &[
+				("certificates_file", &tls_config.certificates_file),
+				("private_key_file", &tls_config.private_key_file),
+			] {
+				match metadata(file) {
+					Ok(md) if md.is_file() => {}
+					_ => errors.push(format!(
+						"http_config.evh_config.tls_config.{} '{}' is not a file",
+						key, file
+					)),
+				}
+			}
+		}
+		None => {}
+	}
+
+	match errors.is_empty() {
+		true => Ok(()),
+		false => Err(ErrorKind::SetupError(format!(
+			"invalid configuration: {}",
+			errors.join(", ")
+		))
+		.into()),
+	}
+}
+
+/// Returns the specified configuration in the format of the configuration file.
+pub fn to_toml(config: &RustletConfig) -> Result<String, Error> {
+	let http_config = &config.http_config;
+	let file = ConfigFile {
+		session_timeout: Some(config.session_timeout),
+		session_file: config.session_file.clone(),
+		plugins: Some(config.plugins),
+		http_config: Some(HttpConfigFile {
+			host: Some(http_config.host.clone()),
+			port: Some(http_config.port),
+			root_dir: Some(http_config.root_dir.clone()),
+			server_name: Some(http_config.server_name.clone()),
+			request_log_params: Some(http_config.request_log_params.clone()),
+			request_log_separator_char: Some(http_config.request_log_separator_char),
+			request_log_max_size: Some(http_config.request_log_max_size),
+			request_log_max_age_millis: Some(to_u64(http_config.request_log_max_age_millis)),
+			delete_request_rotation: Some(http_config.delete_request_rotation),
+			main_log_max_size: Some(http_config.main_log_max_size),
+			main_log_max_age_millis: Some(to_u64(http_config.main_log_max_age_millis)),
+			stats_log_max_size: Some(http_config.stats_log_max_size),
+			stats_log_max_age_millis: Some(to_u64(http_config.stats_log_max_age_millis)),
+			last_request_timeout: Some(to_u64(http_config.last_request_timeout)),
+			stats_frequency: Some(http_config.stats_frequency),
+			read_timeout: Some(to_u64(http_config.read_timeout)),
+			max_log_queue: Some(http_config.max_log_queue),
+			debug: Some(http_config.debug),
+			evh_config: Some(EvhConfigFile {
+				thread_count: Some(http_config.evh_config.thread_count),
+				tls_config: http_config
+					.evh_config
+					.tls_config
+					.as_ref()
+					.map(|tls_config| TlsConfigFile {
+						certificates_file: Some(tls_config.certificates_file.clone()),
+						private_key_file: Some(tls_config.private_key_file.clone()),
+					}),
+			}),
+		}),
+	};
+	toml::to_string(&file).map_err(|e| {
+		ErrorKind::InternalError(format!("couldn't serialize configuration: {}", e)).into()
+	})
+}
+
+fn to_u64(value: u128) -> u64 {
+	value.try_into().unwrap_or(u64::MAX)
+}
+
+// returns the message of the error of loading the configuration, or an empty string if it
+// was loaded.
+#[cfg(test)]
+fn load_error(result: Result<RustletConfig, Error>) -> String {
+	match result {
+		Ok(_) => "".to_string(),
+		Err(e) => match e.kind() {
+			ErrorKind::SetupError(message) => message,
+			kind => format!("{:?}", kind),
+		},
+	}
+}
+
+#[test]
+fn test_config_precedence() -> Result<(), Error> {
+	let yml = clap::load_yaml!("rustlet.yml");
+	let file = format!(
+		"{}/rustlet_config_{}.toml",
+		std::env::temp_dir().display(),
+		std::process::id()
+	);
+	std::fs::write(
+		&file,
+		"session_timeout = 120\n\
+		[http_config]\n\
+		host = \"0.0.0.0\"\n\
+		port = 8000\n\
+		server_name = \"file\"\n\
+		request_log_params = [\"abc\"]\n",
+	)?;
+
+	// the environment overrides the file and the command line overrides both
+	std::env::set_var("RUSTLET_HTTP_CONFIG_PORT", "8001");
+	std::env::set_var("RUSTLET_HTTP_CONFIG_SERVER_NAME", "1.0");
+	let env_config = load(
+		&clap::App::from_yaml(yml).get_matches_from(vec!["rustlet", "-f", &file]),
+		"test",
+	);
+	let args_config = load(
+		&clap::App::from_yaml(yml).get_matches_from(vec![
+			"rustlet",
+			"-f",
+			&file,
+			"-d",
+			"--set",
+			"http_config.port=8002",
+			"--set",
+			"http_config.request_log_params=[\"def\", \"ghi\"]",
+		]),
+		"test",
+	);
+	std::env::remove_var("RUSTLET_HTTP_CONFIG_PORT");
+	std::env::remove_var("RUSTLET_HTTP_CONFIG_SERVER_NAME");
+	let file_config = load(
+		&clap::App::from_yaml(yml).get_matches_from(vec!["rustlet", "-f", &file]),
+		"test",
+	);
+	let _ = std::fs::remove_file(&file);
+
+	let file_config = file_config?;
+	assert_eq!(file_config.session_timeout, 120);
+	assert_eq!(file_config.http_config.host, "0.0.0.0");
+	assert_eq!(file_config.http_config.port, 8000);
+	assert_eq!(file_config.http_config.server_name, "file");
+	assert!(!file_config.http_config.debug);
+	// keys that are not specified have their default value
+	assert_eq!(file_config.http_config.evh_config.thread_count, 8);
+	assert_eq!(file_config.http_config.max_log_queue, 100_000);
+
+	let env_config = env_config?;
+	assert_eq!(env_config.http_config.port, 8001);
+	// string keys are never parsed as other values
+	assert_eq!(env_config.http_config.server_name, "1.0");
+	assert_eq!(env_config.http_config.request_log_params, vec!["abc"]);
+
+	let args_config = args_config?;
+	assert_eq!(args_config.session_timeout, 120);
+	assert_eq!(args_config.http_config.port, 8002);
+	assert_eq!(args_config.http_config.server_name, "1.0");
+	assert_eq!(
+		args_config.http_config.request_log_params,
+		vec!["def", "ghi"]
+	);
+	assert!(args_config.http_config.debug);
+
+	// the effective configuration is a valid configuration file
+	let effective = to_toml(&args_config)?;
+	std::fs::write(&file, &effective)?;
+	let reloaded = load(
+		&clap::App::from_yaml(yml).get_matches_from(vec!["rustlet", "-f", &file]),
+		"test",
+	);
+	let _ = std::fs::remove_file(&file);
+	assert_eq!(to_toml(&reloaded?)?, effective);
+	Ok(())
+}
+
+#[test]
+fn test_config_validation() -> Result<(), Error> {
+	let yml = clap::load_yaml!("rustlet.yml");
+	let load_args = |args: Vec<&str>| {
+		let mut command_line = vec!["rustlet"];
+		command_line.extend(args);
+		load_error(load(
+			&clap::App::from_yaml(yml).get_matches_from(command_line),
+			"test",
+		))
+	};
+
+	assert_eq!(load_args(vec![]), "");
+	let error = load_args(vec![
+		"--set",
+		"http_config.port=0",
+		"--set",
+		"http_config.host=",
+		"--set",
+		"http_config.evh_config.thread_count=0",
+		"--set",
+		"http_config.read_timeout=0",
+	]);
+	for expected in &[
+		"http_config.host must not be empty",
+		"http_config.port must not be 0",
+		"http_config.evh_config.thread_count must be at least 1",
+		"http_config.read_timeout must be at least 1",
+	] {
+		assert!(error.contains(expected), "{}", error);
+	}
+
+	// TLS requires both files and they must exist
+	let error = load_args(vec!["--certs", "cert.pem"]);
+	assert!(error.contains("either both"), "{}", error);
+	let error = load_args(vec![
+		"--certs",
+		"/nonexistent",
+		"--private_key",
+		"Cargo.toml",
+	]);
+	assert!(error.contains("certificates_file '/nonexistent' is not a file"));
+	assert!(!error.contains("private_key_file"), "{}", error);
+
+	// unknown keys, values of the wrong type and malformed settings are rejected
+	assert!(load_args(vec!["--set", "http_config.unknown=1"]).contains("unknown field"));
+	assert!(load_args(vec!["--set", "http_config.port=abc"]).contains("invalid configuration"));
+	assert!(load_args(vec!["--set", "http_config.port"]).contains("expected key=value"));
+	assert!(load_args(vec![
+		"--set",
+		"http_config.port=1",
+		"--set",
+		"http_config.port.x=1"
+	])
+	.contains("is not a table"));
+	assert!(load_args(vec!["-f", "/nonexistent.toml"]).contains("couldn't open config file"));
+	Ok(())
+}
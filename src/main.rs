@@ -20,14 +20,19 @@ use std::os::unix::io::AsRawFd;
 
 use clap::load_yaml;
 use clap::App;
+use librustlet::declaration::rustlet as declare;
+use librustlet::extract::Query;
 use librustlet::*;
 use native_tls::TlsConnector;
 use nioruntime_log::*;
+use serde::Deserialize;
 use std::io::Read;
 use std::io::Write;
 use std::net::TcpStream;
 use std::sync::{Arc, Mutex};
 
+mod config;
+
 const MAX_BUF: usize = 100_000;
 const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
@@ -53,6 +58,17 @@ fn fun2() -> Result<(), Error> {
 	Ok(())
 }
 
+#[derive(Deserialize)]
+struct SessionParams {
+	abc: u32,
+}
+
+#[declare(path = "/set_session")]
+fn set_session(Query(params): Query<SessionParams>) -> Result<(), Error> {
+	session!("abc", Example::new(params.abc));
+	Ok(())
+}
+
 // include build information
 pub mod built_info {
 	include!(concat!(env!("OUT_DIR"), "/built.rs"));
@@ -211,7 +227,7 @@ fn client_thread(
 	Ok(())
 }
 
-#[derive(Debug)]
+#[derive(Debug, Readable, Writeable)]
 struct Example {
 	num: u32,
 }
@@ -222,22 +238,7 @@ impl Example {
 	}
 }
 
-impl Writeable for Example {
-	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
-		writer.write_u32(self.num)?;
-
-		Ok(())
-	}
-}
-
-impl Readable for Example {
-	fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
-		let num = reader.read_u32()?;
-		Ok(Example { num })
-	}
-}
-
-#[derive(Debug)]
+#[derive(Debug, Readable, Writeable)]
 struct Example2 {
 	num: u64,
 }
@@ -248,21 +249,6 @@ impl Example2 {
 	}
 }
 
-impl Writeable for Example2 {
-	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), Error> {
-		writer.write_u64(self.num)?;
-
-		Ok(())
-	}
-}
-
-impl Readable for Example2 {
-	fn read<R: Reader>(reader: &mut R) -> Result<Self, Error> {
-		let num = reader.read_u64()?;
-		Ok(Example2 { num })
-	}
-}
-
 fn main() {
 	let yml = load_yaml!("rustlet.yml");
 	let args = App::from_yaml(yml)
@@ -272,28 +258,6 @@ fn main() {
 	let client = args.is_present("client");
 	let mio = args.is_present("mio");
 	let nginx = args.is_present("nginx");
-	let debug = args.is_present("debug");
-	let delete_request_rotation = args.is_present("delete_request_rotation");
-
-	let certs = args.is_present("certs");
-	let private_key = args.is_present("private_key");
-
-	if certs && !private_key || !certs && private_key {
-		error!("Either both cert and private_key or neither must be specified");
-		return;
-	}
-
-	let tls_config = match args.value_of("certs") {
-		Some(certificates_file) => {
-			let private_key_file = args.value_of("private_key").unwrap_or("").to_string();
-			let certificates_file = certificates_file.to_string();
-			Some(TlsConfig {
-				certificates_file,
-				private_key_file,
-			})
-		}
-		None => None,
-	};
 
 	if client {
 		let threads = args.is_present("threads");
@@ -383,21 +347,26 @@ fn main() {
 		info!("Average latency={}ms", avg_lat,);
 		info!("Max latency={}ms", (*lat_max) as f64 / (1_000_000 as f64));
 	} else {
-		rustlet_init!(RustletConfig {
-			session_timeout: 60,
-			http_config: HttpConfig {
-				evh_config: EventHandlerConfig {
-					thread_count: 8,
-					tls_config,
-				},
-				max_log_queue: 100_000,
-				stats_frequency: 10_000,
-				delete_request_rotation,
-				debug,
-				server_name: format!("Rustlet Httpd {}", VERSION),
-				..Default::default()
-			},
-		});
+		let config = match config::load(&args, VERSION) {
+			Ok(config) => config,
+			Err(e) => {
+				error!("{}", e);
+				std::process::exit(1);
+			}
+		};
+		let effective_config = config::to_toml(&config);
+		rustlet_init!(config);
+
+		const MAIN_LOG: &str = "mainlog";
+		match effective_config {
+			Ok(effective_config) => {
+				log_multi!(INFO, MAIN_LOG, "Effective configuration:");
+				for line in effective_config.lines() {
+					log_no_ts_multi!(INFO, MAIN_LOG, "{}", line);
+				}
+			}
+			Err(e) => log_multi!(ERROR, MAIN_LOG, "{}", e),
+		}
 
 		rustlet!("empty", {});
 
@@ -413,11 +382,6 @@ fn main() {
 			}
 		});
 
-		rustlet!("set_session", {
-			let val: u32 = query!("abc").parse()?;
-			session!("abc", Example::new(val));
-		});
-
 		rustlet!("delete_session", {
 			session_delete!();
 		});
@@ -525,12 +489,18 @@ fn main() {
 		rustlet_mapping!("/async", "async");
 		rustlet_mapping!("/cookies", "cookies");
 		rustlet_mapping!("/empty", "empty");
-		rustlet_mapping!("/set_session", "set_session");
 		rustlet_mapping!("/get_session", "get_session");
 		rustlet_mapping!("/delete_session", "delete_session");
 		rustlet_mapping!("/delete_abc", "delete_abc");
 		rustlet_mapping!("/content", "content");
 
-		std::thread::park();
+		// stop gracefully on SIGTERM/SIGINT
+		let (tx, rx) = std::sync::mpsc::channel();
+		ctrlc::set_handler(move || {
+			let _ = tx.send(());
+		})
+		.expect("couldn't set signal handler");
+		let _ = rx.recv();
+		rustlet_shutdown!(std::time::Duration::from_secs(30));
 	}
 }